@@ -0,0 +1,211 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use git2::Oid;
+
+use trees::CachedTree;
+
+#[derive(Clone)]
+enum Cached {
+    Tree(Arc<CachedTree>),
+    Blob(Arc<Vec<u8>>)
+}
+
+struct Entry {
+    value: Cached,
+    weight: usize,
+    last_use: u64
+}
+
+/// Inflated blobs and parsed trees, keyed by oid. Objects are
+/// content-addressed, so it does not matter which repository one came from.
+/// The least recently used objects are dropped once the total size exceeds
+/// the byte budget.
+pub struct ObjectCache {
+    entries: HashMap<Oid, Entry>,
+    order: BTreeMap<u64, Oid>,
+    next_use: u64,
+    used: usize,
+    budget: usize,
+    pub hits: u64,
+    pub misses: u64
+}
+
+impl ObjectCache {
+    pub fn new(budget: usize) -> ObjectCache {
+        ObjectCache {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            next_use: 0,
+            used: 0,
+            budget: budget,
+            hits: 0,
+            misses: 0
+        }
+    }
+
+    pub fn get_tree(&mut self, oid: &Oid) -> Option<Arc<CachedTree>> {
+        match self.get(oid) {
+            Some(Cached::Tree(tree)) => Some(tree),
+            _ => None
+        }
+    }
+
+    pub fn get_blob(&mut self, oid: &Oid) -> Option<Arc<Vec<u8>>> {
+        match self.get(oid) {
+            Some(Cached::Blob(blob)) => Some(blob),
+            _ => None
+        }
+    }
+
+    pub fn insert_tree(&mut self, oid: Oid, tree: Arc<CachedTree>) {
+        let weight = tree.weight();
+        self.insert(oid, Cached::Tree(tree), weight);
+    }
+
+    pub fn insert_blob(&mut self, oid: Oid, blob: Arc<Vec<u8>>) {
+        let weight = blob.len();
+        self.insert(oid, Cached::Blob(blob), weight);
+    }
+
+    fn get(&mut self, oid: &Oid) -> Option<Cached> {
+        let use_ = self.next_use;
+
+        let value = match self.entries.get_mut(oid) {
+            Some(entry) => {
+                self.order.remove(&entry.last_use);
+                entry.last_use = use_;
+                entry.value.clone()
+            }
+            None => {
+                self.misses += 1;
+                return None;
+            }
+        };
+
+        self.order.insert(use_, *oid);
+        self.next_use += 1;
+        self.hits += 1;
+
+        Some(value)
+    }
+
+    fn insert(&mut self, oid: Oid, value: Cached, weight: usize) {
+        // Something bigger than the whole budget would only flush everything
+        // else out and then be evicted itself
+        if weight > self.budget {
+            return;
+        }
+
+        if let Some(old) = self.entries.remove(&oid) {
+            self.order.remove(&old.last_use);
+            self.used -= old.weight;
+        }
+
+        while self.used + weight > self.budget {
+            let (use_, victim) = match self.order.iter().next() {
+                Some((&use_, &victim)) => (use_, victim),
+                None => break
+            };
+            self.order.remove(&use_);
+            if let Some(old) = self.entries.remove(&victim) {
+                self.used -= old.weight;
+            }
+        }
+
+        self.entries.insert(oid, Entry {
+            value: value,
+            weight: weight,
+            last_use: self.next_use
+        });
+        self.order.insert(self.next_use, oid);
+        self.next_use += 1;
+        self.used += weight;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use git2::Oid;
+
+    use super::ObjectCache;
+
+    fn oid(n: u8) -> Oid {
+        Oid::from_bytes(&[n; 20]).unwrap()
+    }
+
+    fn blob(len: usize) -> Arc<Vec<u8>> {
+        Arc::new(vec![0; len])
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted_first() {
+        let mut cache = ObjectCache::new(30);
+
+        cache.insert_blob(oid(1), blob(10));
+        cache.insert_blob(oid(2), blob(10));
+        cache.insert_blob(oid(3), blob(10));
+
+        // Using 1 again leaves 2 as the oldest
+        assert!(cache.get_blob(&oid(1)).is_some());
+        cache.insert_blob(oid(4), blob(10));
+
+        assert!(cache.get_blob(&oid(2)).is_none());
+        assert!(cache.get_blob(&oid(1)).is_some());
+        assert!(cache.get_blob(&oid(3)).is_some());
+        assert!(cache.get_blob(&oid(4)).is_some());
+    }
+
+    #[test]
+    fn evicts_until_the_new_object_fits() {
+        let mut cache = ObjectCache::new(30);
+
+        cache.insert_blob(oid(1), blob(10));
+        cache.insert_blob(oid(2), blob(10));
+        cache.insert_blob(oid(3), blob(10));
+        cache.insert_blob(oid(4), blob(25));
+
+        assert_eq!(cache.used, 25);
+        assert!(cache.get_blob(&oid(1)).is_none());
+        assert!(cache.get_blob(&oid(2)).is_none());
+        assert!(cache.get_blob(&oid(3)).is_none());
+        assert!(cache.get_blob(&oid(4)).is_some());
+    }
+
+    #[test]
+    fn reinserting_does_not_count_twice() {
+        let mut cache = ObjectCache::new(30);
+
+        cache.insert_blob(oid(1), blob(10));
+        cache.insert_blob(oid(1), blob(10));
+
+        assert_eq!(cache.used, 10);
+        assert_eq!(cache.entries.len(), cache.order.len());
+    }
+
+    #[test]
+    fn objects_over_budget_are_not_cached() {
+        let mut cache = ObjectCache::new(30);
+
+        cache.insert_blob(oid(1), blob(10));
+        cache.insert_blob(oid(2), blob(31));
+
+        assert_eq!(cache.used, 10);
+        assert!(cache.get_blob(&oid(1)).is_some());
+        assert!(cache.get_blob(&oid(2)).is_none());
+    }
+
+    #[test]
+    fn counts_hits_and_misses() {
+        let mut cache = ObjectCache::new(30);
+
+        assert!(cache.get_blob(&oid(1)).is_none());
+        cache.insert_blob(oid(1), blob(10));
+        assert!(cache.get_blob(&oid(1)).is_some());
+        assert!(cache.get_blob(&oid(1)).is_some());
+
+        assert_eq!((cache.hits, cache.misses), (2, 1));
+    }
+}
@@ -0,0 +1,94 @@
+use std::fmt;
+use std::io;
+use std::result;
+
+use libc::{c_int, ENOENT, EIO, EISDIR, ENOTDIR, EINVAL};
+
+use git2::{self, ErrorCode};
+
+use time;
+
+/// Everything that can go wrong while servicing a single filesystem request.
+/// Each variant maps onto the errno that gets sent back to the kernel, so a
+/// bad request fails on its own instead of taking the whole mount down.
+#[derive(Debug)]
+pub enum Error {
+    /// libgit2 failed; missing objects become ENOENT, anything else EIO
+    Git(git2::Error),
+    /// Reading or writing a file of our own failed
+    Io(io::Error),
+    /// The kernel referred to an inode we never handed out
+    UnknownInode(u64),
+    /// The kernel referred to an inode whose path the mounted revision no
+    /// longer has
+    StaleInode(u64),
+    /// A name lookup did not match any tree entry
+    NotFound,
+    /// A directory operation was attempted on something that is not a tree
+    NotADirectory(u64),
+    /// A file operation was attempted on a tree
+    IsADirectory(u64),
+    /// readlink was called on something that is not a symlink
+    NotASymlink(u64),
+    /// A tree entry carries a filemode we cannot represent
+    UnexpectedMode(i32),
+    /// A time could not be formatted for a commit metadata file
+    TimeFormat(time::ParseError)
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl Error {
+    pub fn errno(&self) -> c_int {
+        match *self {
+            Error::Git(ref e) => match e.code() {
+                ErrorCode::NotFound => ENOENT,
+                _ => EIO
+            },
+            Error::Io(ref e) => e.raw_os_error().unwrap_or(EIO),
+            Error::UnknownInode(_) => ENOENT,
+            Error::StaleInode(_) => ENOENT,
+            Error::NotFound => ENOENT,
+            Error::NotADirectory(_) => ENOTDIR,
+            Error::IsADirectory(_) => EISDIR,
+            Error::NotASymlink(_) => EINVAL,
+            Error::UnexpectedMode(_) => EIO,
+            Error::TimeFormat(_) => EIO
+        }
+    }
+}
+
+impl From<git2::Error> for Error {
+    fn from(e: git2::Error) -> Error {
+        Error::Git(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<time::ParseError> for Error {
+    fn from(e: time::ParseError) -> Error {
+        Error::TimeFormat(e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Git(ref e) => write!(f, "git error: {}", e),
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::UnknownInode(ino) => write!(f, "unknown inode {}", ino),
+            Error::StaleInode(ino) => write!(f, "inode {} is no longer in the mounted revision", ino),
+            Error::NotFound => write!(f, "no such entry"),
+            Error::NotADirectory(ino) => write!(f, "inode {} is not a directory", ino),
+            Error::IsADirectory(ino) => write!(f, "inode {} is a directory", ino),
+            Error::NotASymlink(ino) => write!(f, "inode {} is not a symlink", ino),
+            Error::UnexpectedMode(mode) => write!(f, "unexpected filemode: {:o}", mode),
+            Error::TimeFormat(ref e) => write!(f, "time format error: {}", e)
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use std::collections::HashMap;
+
+/// State for open files or directories, indexed by the `fh` handed to the
+/// kernel from `open`/`opendir` and given back on every later call.
+pub struct Handles<T> {
+    open: HashMap<u64, T>,
+    next_fh: u64
+}
+
+impl<T> Handles<T> {
+    pub fn new() -> Handles<T> {
+        Handles {
+            open: HashMap::new(),
+            // fh 0 is what the kernel passes when nothing was opened
+            next_fh: 1
+        }
+    }
+
+    pub fn insert(&mut self, value: T) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.open.insert(fh, value);
+        fh
+    }
+
+    pub fn get_mut(&mut self, fh: u64) -> Option<&mut T> {
+        self.open.get_mut(&fh)
+    }
+
+    pub fn remove(&mut self, fh: u64) -> Option<T> {
+        self.open.remove(&fh)
+    }
+}
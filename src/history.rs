@@ -0,0 +1,413 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str;
+
+use git2::{Delta, ObjectType, Oid, Repository};
+
+use error;
+
+// Where the index is kept, relative to the git directory; each revision
+// that gets mounted has its own, named by a hash of the revspec
+const INDEX_PREFIX: &str = "gitfs-history-";
+
+// First record of the file, followed by the indexed commit
+const INDEX_MAGIC: &[u8] = b"gitfs-history 1 ";
+
+/// A commit that changed a path, with its committer time.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Change {
+    pub commit: Oid,
+    pub time: i64
+}
+
+/// When a path last changed, and when it first appeared.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PathHistory {
+    pub modified: Change,
+    pub added: Change
+}
+
+/// The history of every path in one revision, following first parents
+/// only: whatever a merge brings in is dated at the merge. A directory
+/// changes whenever anything below it does, and the root is the empty path.
+pub struct PathIndex {
+    head: Option<Oid>,
+    paths: HashMap<PathBuf, PathHistory>
+}
+
+fn corrupt() -> error::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "corrupt history index").into()
+}
+
+fn parse_oid(bytes: &[u8]) -> error::Result<Oid> {
+    // Oid::from_str would take a truncated id as a prefix
+    if bytes.len() != 40 {
+        return Err(corrupt());
+    }
+    let s = str::from_utf8(bytes).map_err(|_| corrupt())?;
+    Oid::from_str(s).map_err(|_| corrupt())
+}
+
+fn parse_time(bytes: &[u8]) -> error::Result<i64> {
+    let s = str::from_utf8(bytes).map_err(|_| corrupt())?;
+    s.parse().map_err(|_| corrupt())
+}
+
+impl PathIndex {
+    pub fn new() -> PathIndex {
+        PathIndex {
+            head: None,
+            paths: HashMap::new()
+        }
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&PathHistory> {
+        self.paths.get(path)
+    }
+
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    /// Load the index saved in `repo` for `rev`, bring it up to `head`, the
+    /// commit `rev` currently names, and save it back. When `head` is a
+    /// first-parent descendant of the saved revision only the new commits
+    /// are replayed.
+    pub fn open(repo: &Repository, rev: &str, head: Oid) -> error::Result<PathIndex> {
+        // Mounts of different revisions would keep replacing one shared
+        // index with a full rebuild of their own
+        let key = Oid::hash_object(ObjectType::Blob, rev.as_bytes())?;
+        let file = repo.path().join(format!("{}{}", INDEX_PREFIX, key));
+
+        // A missing or unreadable index is simply rebuilt
+        let mut index = PathIndex::load(&file).unwrap_or_else(|_| PathIndex::new());
+
+        if index.head != Some(head) {
+            index.update(repo, head)?;
+
+            // The git directory of someone else's repository is often not
+            // ours to write; the index is just as good for this mount
+            if let Err(e) = index.save(&file) {
+                println!("failed to save history index to {}: {}", file.display(), e);
+            }
+        }
+
+        Ok(index)
+    }
+
+    /// Advance the index to `head`, starting over if the indexed revision
+    /// is not on its first-parent chain.
+    pub fn update(&mut self, repo: &Repository, head: Oid) -> error::Result<()> {
+        // Commits not yet indexed, newest first
+        let mut pending = Vec::new();
+        let mut next = Some(head);
+        let mut found = false;
+
+        while let Some(id) = next {
+            if Some(id) == self.head {
+                found = true;
+                break;
+            }
+            next = repo.find_commit(id)?.parent_ids().next();
+            pending.push(id);
+        }
+
+        if !found {
+            self.head = None;
+            self.paths.clear();
+        }
+
+        for &id in pending.iter().rev() {
+            self.apply(repo, id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Record the changes `id` made relative to its first parent.
+    fn apply(&mut self, repo: &Repository, id: Oid) -> error::Result<()> {
+        let commit = repo.find_commit(id)?;
+        let change = Change {
+            commit: id,
+            time: commit.time().seconds()
+        };
+
+        let tree = commit.tree()?;
+        let parent = match commit.parent_ids().next() {
+            Some(p) => Some(repo.find_commit(p)?.tree()?),
+            None => None
+        };
+
+        let diff = repo.diff_tree_to_tree(parent.as_ref(), Some(&tree), None)?;
+
+        for delta in diff.deltas() {
+            let deleted = delta.status() == Delta::Deleted;
+            let path = if deleted { delta.old_file().path() } else { delta.new_file().path() };
+            let path = match path {
+                Some(path) => path,
+                None => continue
+            };
+
+            for p in path.ancestors() {
+                // A deletion takes any directories it leaves empty with it
+                if deleted && !p.as_os_str().is_empty() && tree.get_path(p).is_err() {
+                    self.paths.remove(p);
+                } else {
+                    self.touch(p, change);
+                }
+            }
+        }
+
+        self.head = Some(id);
+        Ok(())
+    }
+
+    fn touch(&mut self, path: &Path, change: Change) {
+        let history = self.paths.entry(path.to_path_buf()).or_insert(PathHistory {
+            modified: change,
+            added: change
+        });
+        history.modified = change;
+    }
+
+    /// The file is a header and then one record per path, each ended by a
+    /// NUL since paths may contain anything else:
+    /// `<modified> <time> <added> <time> <path>`.
+    fn load(file: &Path) -> error::Result<PathIndex> {
+        let mut data = Vec::new();
+        File::open(file)?.read_to_end(&mut data)?;
+
+        let mut records = data.split(|&b| b == 0);
+
+        let header = records.next().unwrap_or(b"");
+        if !header.starts_with(INDEX_MAGIC) {
+            return Err(corrupt());
+        }
+        let head = parse_oid(&header[INDEX_MAGIC.len()..])?;
+
+        let mut paths = HashMap::new();
+        for record in records {
+            // The last record is followed by a NUL too
+            if record.is_empty() {
+                continue;
+            }
+
+            let fields: Vec<&[u8]> = record.splitn(5, |&b| b == b' ').collect();
+            if fields.len() != 5 {
+                return Err(corrupt());
+            }
+
+            paths.insert(PathBuf::from(OsStr::from_bytes(fields[4])), PathHistory {
+                modified: Change { commit: parse_oid(fields[0])?, time: parse_time(fields[1])? },
+                added: Change { commit: parse_oid(fields[2])?, time: parse_time(fields[3])? }
+            });
+        }
+
+        Ok(PathIndex {
+            head: Some(head),
+            paths: paths
+        })
+    }
+
+    fn save(&self, file: &Path) -> error::Result<()> {
+        let head = match self.head {
+            Some(head) => head,
+            None => return Ok(())
+        };
+
+        // Write a new file and rename it over the old one, so a crash never
+        // leaves half an index behind. The temporary name is our own, since
+        // another mount of the same revision may be saving at the same time.
+        let mut tmp = file.as_os_str().to_os_string();
+        tmp.push(format!(".tmp.{}", process::id()));
+        let tmp = PathBuf::from(tmp);
+
+        let res = self.write(head, &tmp).and_then(|()| Ok(fs::rename(&tmp, file)?));
+        if res.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+
+        res
+    }
+
+    fn write(&self, head: Oid, file: &Path) -> error::Result<()> {
+        let mut w = BufWriter::new(File::create(file)?);
+        w.write_all(INDEX_MAGIC)?;
+        write!(w, "{}\0", head)?;
+
+        for (path, h) in self.paths.iter() {
+            write!(w, "{} {} {} {} ", h.modified.commit, h.modified.time, h.added.commit, h.added.time)?;
+            w.write_all(path.as_os_str().as_bytes())?;
+            w.write_all(b"\0")?;
+        }
+
+        w.flush()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use git2::{ObjectType, Oid};
+
+    use testutil::TestRepo;
+
+    use super::{Change, PathIndex};
+
+    // c1 -> c2 -> c3 along first parents, with `side` branching off c1
+    fn history(repo: &TestRepo) -> (Oid, Oid, Oid, Oid) {
+        let c1 = repo.commit(None, 100, &[("a", "1"), ("d/b", "1")]);
+        let c2 = repo.commit(Some(c1), 200, &[("a", "2"), ("d/b", "1"), ("d/c", "1")]);
+        let c3 = repo.commit(Some(c2), 300, &[("d/c", "1")]);
+        let side = repo.commit(Some(c1), 250, &[("a", "1"), ("d/b", "1"), ("e", "1")]);
+        (c1, c2, c3, side)
+    }
+
+    fn build(repo: &TestRepo, head: Oid) -> PathIndex {
+        let mut index = PathIndex::new();
+        index.update(&repo.repo, head).unwrap();
+        index
+    }
+
+    fn times(index: &PathIndex, path: &str) -> Option<(i64, i64)> {
+        index.get(Path::new(path)).map(|h| (h.modified.time, h.added.time))
+    }
+
+    fn assert_same(a: &PathIndex, b: &PathIndex) {
+        assert_eq!(a.head, b.head);
+        assert_eq!(a.paths, b.paths);
+    }
+
+    #[test]
+    fn records_last_change_and_first_add() {
+        let repo = TestRepo::new("history-records");
+        let (c1, c2, _, _) = history(&repo);
+
+        let index = build(&repo, c2);
+
+        assert_eq!(index.head, Some(c2));
+        assert_eq!(times(&index, ""), Some((200, 100)));
+        assert_eq!(times(&index, "a"), Some((200, 100)));
+        assert_eq!(times(&index, "d"), Some((200, 100)));
+        assert_eq!(times(&index, "d/b"), Some((100, 100)));
+        assert_eq!(times(&index, "d/c"), Some((200, 200)));
+        assert_eq!(index.get(Path::new("d/b")).unwrap().modified, Change { commit: c1, time: 100 });
+    }
+
+    #[test]
+    fn deletions_remove_paths() {
+        let repo = TestRepo::new("history-deletions");
+        let (_, _, c3, _) = history(&repo);
+
+        let index = build(&repo, c3);
+
+        assert_eq!(times(&index, "a"), None);
+        assert_eq!(times(&index, "d/b"), None);
+        assert_eq!(times(&index, "d"), Some((300, 100)));
+        assert_eq!(times(&index, "d/c"), Some((200, 200)));
+        assert_eq!(index.len(), 3);
+    }
+
+    #[test]
+    fn incremental_update_matches_rebuild() {
+        let repo = TestRepo::new("history-incremental");
+        let (_, c2, c3, _) = history(&repo);
+
+        let mut index = build(&repo, c2);
+        index.update(&repo.repo, c3).unwrap();
+
+        assert_same(&index, &build(&repo, c3));
+    }
+
+    #[test]
+    fn update_off_the_first_parent_chain_starts_over() {
+        let repo = TestRepo::new("history-diverged");
+        let (_, _, c3, side) = history(&repo);
+
+        let mut index = build(&repo, c3);
+        index.update(&repo.repo, side).unwrap();
+
+        assert_same(&index, &build(&repo, side));
+        assert_eq!(times(&index, "e"), Some((250, 250)));
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let repo = TestRepo::new("history-round-trip");
+        let (_, _, c3, _) = history(&repo);
+        let file = repo.path.join("index");
+
+        let index = build(&repo, c3);
+        index.save(&file).unwrap();
+
+        assert_same(&PathIndex::load(&file).unwrap(), &index);
+    }
+
+    #[test]
+    fn load_rejects_corrupt_files() {
+        let repo = TestRepo::new("history-corrupt");
+        let file = repo.path.join("index");
+
+        fs::write(&file, b"not an index\0").unwrap();
+        assert!(PathIndex::load(&file).is_err());
+
+        fs::write(&file, b"gitfs-history 1 0123\0").unwrap();
+        assert!(PathIndex::load(&file).is_err());
+    }
+
+    #[test]
+    fn unwritable_index_is_still_returned() {
+        let repo = TestRepo::new("history-unwritable");
+        let (_, c2, _, _) = history(&repo);
+
+        // A directory where the index should be makes every save fail
+        let key = Oid::hash_object(ObjectType::Blob, b"main").unwrap();
+        fs::create_dir(repo.path.join(format!("gitfs-history-{}", key))).unwrap();
+
+        let index = PathIndex::open(&repo.repo, "main", c2).unwrap();
+
+        assert_same(&index, &build(&repo, c2));
+
+        // Nothing is left behind by the failed save
+        assert!(fs::read_dir(&repo.path).unwrap().all(|e| {
+            !e.unwrap().file_name().to_string_lossy().contains(".tmp")
+        }));
+    }
+
+    #[test]
+    fn each_revision_has_its_own_file() {
+        let repo = TestRepo::new("history-per-rev");
+        let (_, c2, c3, _) = history(&repo);
+
+        PathIndex::open(&repo.repo, "main", c3).unwrap();
+        PathIndex::open(&repo.repo, "old", c2).unwrap();
+
+        let mut names: Vec<String> = fs::read_dir(&repo.path).unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("gitfs-history"))
+            .collect();
+        names.sort();
+
+        assert_eq!(names.len(), 2);
+        assert!(names.iter().all(|name| !name.contains(".tmp")));
+
+        // The index of one revision is still there after the other's save
+        let mut main = PathIndex::new();
+        for name in names {
+            let index = PathIndex::load(&repo.path.join(name)).unwrap();
+            if index.head == Some(c3) {
+                main = index;
+            }
+        }
+        assert_same(&main, &build(&repo, c3));
+    }
+}
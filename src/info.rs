@@ -0,0 +1,130 @@
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+
+use time::{self, Timespec};
+
+use git2::{Oid, Repository, Signature, Time};
+
+use error::{self, Error};
+use trees::{CachedEntry, CachedTree};
+
+/// Appended to a name in `commits/`, or to a ref in the refs view, to get
+/// that commit's metadata directory instead of its tree. On its own, at the
+/// root of a single revision, it names the metadata of the mounted commit.
+pub const SUFFIX: &str = ".commit";
+
+/// The metadata directory of the commit in `Node::oid`. Like the modes in
+/// `refs`, these are never written by git.
+pub const MODE_COMMIT_INFO: i32 = 0o040005;
+
+/// The `parents/` directory of the commit in `Node::oid`, holding a link to
+/// each parent.
+pub const MODE_PARENTS: i32 = 0o040006;
+
+/// One of the metadata files of the commit in `Node::oid`; which one is
+/// given by the file name.
+pub const MODE_INFO_FILE: i32 = 0o100002;
+
+/// A link from `parents/` to the directory of the parent in `Node::oid`.
+pub const MODE_PARENT_LINK: i32 = 0o120001;
+
+/// Where the `parents/` links of a single revision point, since it has no
+/// `commits/`: a directory at the root, left out of the listing like
+/// `SUFFIX`, that holds the metadata of any commit under its full SHA.
+pub const PARENTS_DIR: &str = ".commit-parents";
+
+/// The `PARENTS_DIR` directory, which lists nothing.
+pub const MODE_PARENT_DIRS: i32 = 0o040010;
+
+const FILES: &[&str] = &["author", "committer", "date", "message", "oid", "tree"];
+
+fn entry(name: &str, oid: Oid, mode: i32) -> CachedEntry {
+    CachedEntry {
+        name: OsString::from(name),
+        oid: oid,
+        mode: mode
+    }
+}
+
+/// The entries of the metadata directory of `commit`.
+pub fn list(commit: Oid) -> CachedTree {
+    let mut entries: Vec<CachedEntry> = FILES.iter().map(|name| entry(name, commit, MODE_INFO_FILE)).collect();
+    entries.push(entry("parents", commit, MODE_PARENTS));
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    CachedTree::from_entries(entries)
+}
+
+/// The entries of `parents/`, links numbered in the order git records the
+/// parents.
+pub fn list_parents(repo: &Repository, commit: Oid) -> error::Result<CachedTree> {
+    let commit = repo.find_commit(commit)?;
+
+    Ok(CachedTree::from_entries(commit.parent_ids().enumerate().map(|(i, parent)| {
+        entry(&i.to_string(), parent, MODE_PARENT_LINK)
+    }).collect()))
+}
+
+/// Look up `name` in `PARENTS_DIR`. Only a full SHA is taken, which is all
+/// the links ever use.
+pub fn lookup_parent(repo: &Repository, name: &OsStr) -> error::Result<CachedEntry> {
+    let commit = match name.to_str() {
+        Some(s) if s.len() == 40 => Oid::from_str(s).map_err(|_| Error::NotFound)?,
+        _ => return Err(Error::NotFound)
+    };
+
+    // Anything but a commit simply does not exist
+    if repo.find_commit(commit).is_err() {
+        return Err(Error::NotFound);
+    }
+
+    Ok(CachedEntry {
+        name: name.to_os_string(),
+        oid: commit,
+        mode: MODE_COMMIT_INFO
+    })
+}
+
+fn format_signature(sig: &Signature) -> Vec<u8> {
+    let mut out = sig.name_bytes().to_vec();
+    out.extend_from_slice(b" <");
+    out.extend_from_slice(sig.email_bytes());
+    out.extend_from_slice(b">\n");
+    out
+}
+
+/// A time the way `git log` shows it, in the committer's own timezone, but
+/// with the day zero-padded so the fields can be split on single spaces.
+fn format_time(t: &Time) -> error::Result<Vec<u8>> {
+    let offset = t.offset_minutes();
+    let tm = time::at_utc(Timespec { sec: t.seconds() + offset as i64 * 60, nsec: 0 });
+    let sign = if offset < 0 { '-' } else { '+' };
+
+    Ok(format!("{} {}{:02}{:02}\n",
+               time::strftime("%a %b %d %H:%M:%S %Y", &tm)?,
+               sign, offset.abs() / 60, offset.abs() % 60).into_bytes())
+}
+
+/// The content of the metadata file `name` of `commit`.
+pub fn content(repo: &Repository, commit: Oid, name: &OsStr) -> error::Result<Vec<u8>> {
+    let commit = repo.find_commit(commit)?;
+
+    match name.to_str() {
+        Some("author") => Ok(format_signature(&commit.author())),
+        Some("committer") => Ok(format_signature(&commit.committer())),
+        Some("date") => format_time(&commit.author().when()),
+        Some("message") => Ok(commit.message_bytes().to_vec()),
+        Some("oid") => Ok(format!("{}\n", commit.id()).into_bytes()),
+        Some("tree") => Ok(format!("{}\n", commit.tree_id()).into_bytes()),
+        _ => Err(Error::NotFound)
+    }
+}
+
+/// Where the `parents/` link at `path` points, reached by climbing back up
+/// to the root: the parent's directory in `commits/` in the refs view, or
+/// its metadata in `PARENTS_DIR` in a single revision.
+pub fn link_target(path: &Path, parent: Oid, refs: bool) -> Vec<u8> {
+    let depth = path.iter().count().saturating_sub(1);
+    let dir = if refs { "commits" } else { PARENTS_DIR };
+    format!("{}{}/{}", "../".repeat(depth), dir, parent).into_bytes()
+}
@@ -0,0 +1,397 @@
+use std::cmp::min;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::path::PathBuf;
+
+use fuse::FUSE_ROOT_ID;
+
+use git2::Oid;
+
+/// An object as it appears at some place in a tree. The same blob can be
+/// both a regular file and a symlink target, so the mode is part of the key.
+/// `repo` indexes `State::repo_paths`, and each worker's handles opened
+/// from them, since objects below a submodule live in the submodule's
+/// repository.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Node {
+    pub repo: usize,
+    pub oid: Oid,
+    pub mode: i32
+}
+
+/// A path in the mounted tree. Every (parent, name) pair gets its own inode,
+/// even when several paths point at the same object.
+pub struct Inode {
+    pub parent: u64,
+    pub name: OsString,
+    pub node: Node,
+    // The path no longer exists in the mounted revision. The inode is kept
+    // until the kernel forgets it, but can no longer be found by name.
+    pub stale: bool,
+    // The path now holds something else, which got an inode of its own. This
+    // one keeps its node for whatever still refers to it, but can no longer
+    // be found by name either.
+    pub detached: bool,
+    // Number of lookups the kernel has not yet forgotten
+    lookups: u64,
+    // Number of inodes in the table whose parent is this one
+    children: u64,
+    // Position in `InodeTable::idle`, while nothing refers to this inode
+    idle: Option<u64>
+}
+
+/// Inodes are kept alive by kernel lookups and by their children. Anything
+/// else is idle and gets evicted, oldest first, once the table grows past
+/// its capacity.
+pub struct InodeTable {
+    inodes: HashMap<u64, Inode>,
+    by_name: HashMap<(u64, OsString), u64>,
+    next_ino: u64,
+    idle: BTreeMap<u64, u64>,
+    next_idle: u64,
+    capacity: usize
+}
+
+impl InodeTable {
+    pub fn new(root: Node, capacity: usize) -> InodeTable {
+        let mut inodes = HashMap::new();
+
+        // The root is its own parent, which gives it the usual `..`
+        inodes.insert(FUSE_ROOT_ID, Inode {
+            parent: FUSE_ROOT_ID,
+            name: OsString::new(),
+            node: root,
+            stale: false,
+            detached: false,
+            lookups: 0,
+            children: 0,
+            idle: None
+        });
+
+        InodeTable {
+            inodes: inodes,
+            by_name: HashMap::new(),
+            next_ino: FUSE_ROOT_ID + 1,
+            idle: BTreeMap::new(),
+            next_idle: 0,
+            capacity: capacity
+        }
+    }
+
+    pub fn get(&self, ino: u64) -> Option<&Inode> {
+        self.inodes.get(&ino)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &Inode)> {
+        self.inodes.iter().map(|(&ino, inode)| (ino, inode))
+    }
+
+    pub fn set_node(&mut self, ino: u64, node: Node) {
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.node = node;
+        }
+    }
+
+    /// Mark `ino` as gone from the mounted revision. Looking up its name
+    /// again gives a new inode.
+    pub fn mark_stale(&mut self, ino: u64) {
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.stale = true;
+        }
+        self.unlink(ino);
+    }
+
+    /// Take `ino` off its path, keeping its node. Looking up its name again
+    /// gives a new inode, for whatever the path holds by then.
+    pub fn detach(&mut self, ino: u64) {
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.detached = true;
+        }
+        self.unlink(ino);
+    }
+
+    fn unlink(&mut self, ino: u64) {
+        let key = match self.inodes.get(&ino) {
+            Some(inode) => (inode.parent, inode.name.clone()),
+            None => return
+        };
+
+        if self.by_name.get(&key) == Some(&ino) {
+            self.by_name.remove(&key);
+        }
+    }
+
+    /// The path of `ino` relative to the root, found by following parents.
+    pub fn path(&self, ino: u64) -> Option<PathBuf> {
+        let mut names = Vec::new();
+        let mut cur = ino;
+
+        while cur != FUSE_ROOT_ID {
+            let inode = self.inodes.get(&cur)?;
+            names.push(&inode.name);
+            cur = inode.parent;
+        }
+
+        Some(names.iter().rev().collect())
+    }
+
+    /// Return the inode for `name` in `parent`, allocating one with `node`
+    /// if this path has not been seen before. An existing inode keeps its
+    /// node, since `node` may come from a listing that is out of date; use
+    /// `set_node` to change it. A new inode starts out idle; call `lookup`
+    /// if it is being handed to the kernel as an entry.
+    pub fn get_or_alloc(&mut self, parent: u64, name: &OsStr, node: Node) -> u64 {
+        let key = (parent, name.to_os_string());
+
+        if let Some(&ino) = self.by_name.get(&key) {
+            return ino;
+        }
+
+        let ino = self.next_ino;
+        self.next_ino += 1;
+
+        self.inodes.insert(ino, Inode {
+            parent: parent,
+            name: key.1.clone(),
+            node: node,
+            stale: false,
+            detached: false,
+            lookups: 0,
+            children: 0,
+            idle: None
+        });
+        self.by_name.insert(key, ino);
+
+        let (mut stale, mut detached) = (false, false);
+        if let Some(p) = self.inodes.get_mut(&parent) {
+            p.children += 1;
+            stale = p.stale;
+            detached = p.detached;
+        }
+        self.update_idle(parent);
+        self.update_idle(ino);
+
+        // Whatever is found below a path that is gone is gone too, and
+        // below a detached one it is just as much out of date
+        if stale {
+            self.mark_stale(ino);
+        } else if detached {
+            self.detach(ino);
+        }
+
+        ino
+    }
+
+    /// Count one kernel reference to `ino`.
+    pub fn lookup(&mut self, ino: u64) {
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.lookups += 1;
+        }
+        self.update_idle(ino);
+        self.evict();
+    }
+
+    /// Drop `nlookup` kernel references to `ino`.
+    pub fn forget(&mut self, ino: u64, nlookup: u64) {
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.lookups -= min(inode.lookups, nlookup);
+        }
+        self.update_idle(ino);
+        self.evict();
+    }
+
+    /// Evict idle inodes until the table is back within its capacity.
+    pub fn evict(&mut self) {
+        while self.inodes.len() > self.capacity {
+            let (seq, ino) = match self.idle.iter().next() {
+                Some((&seq, &ino)) => (seq, ino),
+                None => break
+            };
+
+            self.idle.remove(&seq);
+
+            let inode = self.inodes.remove(&ino).unwrap();
+            let parent = inode.parent;
+
+            // A stale inode's name may belong to a newer one by now
+            let key = (parent, inode.name);
+            if self.by_name.get(&key) == Some(&ino) {
+                self.by_name.remove(&key);
+            }
+
+            if let Some(p) = self.inodes.get_mut(&parent) {
+                p.children -= 1;
+            }
+            self.update_idle(parent);
+        }
+    }
+
+    fn update_idle(&mut self, ino: u64) {
+        let inode = match self.inodes.get_mut(&ino) {
+            Some(inode) => inode,
+            None => return
+        };
+
+        let idle = ino != FUSE_ROOT_ID && inode.lookups == 0 && inode.children == 0;
+
+        match (idle, inode.idle) {
+            (true, None) => {
+                inode.idle = Some(self.next_idle);
+                self.idle.insert(self.next_idle, ino);
+                self.next_idle += 1;
+            }
+            (false, Some(seq)) => {
+                inode.idle = None;
+                self.idle.remove(&seq);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use fuse::FUSE_ROOT_ID;
+
+    use git2::Oid;
+
+    use super::{InodeTable, Node};
+
+    fn node(n: u8) -> Node {
+        Node {
+            repo: 0,
+            oid: Oid::from_bytes(&[n; 20]).unwrap(),
+            mode: 0o100644
+        }
+    }
+
+    #[test]
+    fn existing_path_keeps_its_ino() {
+        let mut nodes = InodeTable::new(node(0), 16);
+
+        let a = nodes.get_or_alloc(FUSE_ROOT_ID, OsStr::new("a"), node(1));
+        let b = nodes.get_or_alloc(FUSE_ROOT_ID, OsStr::new("b"), node(2));
+
+        assert_ne!(a, b);
+        assert_eq!(nodes.get_or_alloc(FUSE_ROOT_ID, OsStr::new("a"), node(3)), a);
+        assert_eq!(nodes.get(a).unwrap().node, node(1));
+        assert_eq!(nodes.path(a).unwrap().to_str(), Some("a"));
+    }
+
+    #[test]
+    fn looked_up_inode_survives_eviction() {
+        let mut nodes = InodeTable::new(node(0), 2);
+
+        let a = nodes.get_or_alloc(FUSE_ROOT_ID, OsStr::new("a"), node(1));
+        nodes.lookup(a);
+
+        for i in 0..10 {
+            nodes.get_or_alloc(FUSE_ROOT_ID, OsStr::new(&i.to_string()), node(2));
+            nodes.evict();
+        }
+
+        assert!(nodes.get(a).is_some());
+        assert_eq!(nodes.get_or_alloc(FUSE_ROOT_ID, OsStr::new("a"), node(1)), a);
+    }
+
+    #[test]
+    fn forgotten_inodes_are_evicted_oldest_first() {
+        let mut nodes = InodeTable::new(node(0), 3);
+
+        let a = nodes.get_or_alloc(FUSE_ROOT_ID, OsStr::new("a"), node(1));
+        let b = nodes.get_or_alloc(FUSE_ROOT_ID, OsStr::new("b"), node(2));
+        nodes.lookup(a);
+        nodes.lookup(b);
+
+        nodes.forget(a, 1);
+        nodes.forget(b, 1);
+        assert!(nodes.get(a).is_some());
+        assert!(nodes.get(b).is_some());
+
+        // One more inode pushes the table over capacity; `a` went idle first
+        let c = nodes.get_or_alloc(FUSE_ROOT_ID, OsStr::new("c"), node(3));
+        nodes.lookup(c);
+
+        assert!(nodes.get(a).is_none());
+        assert!(nodes.get(b).is_some());
+        assert!(nodes.get(c).is_some());
+    }
+
+    #[test]
+    fn parent_outlives_its_children() {
+        let mut nodes = InodeTable::new(node(0), 2);
+
+        let dir = nodes.get_or_alloc(FUSE_ROOT_ID, OsStr::new("dir"), node(1));
+        let file = nodes.get_or_alloc(dir, OsStr::new("file"), node(2));
+        nodes.lookup(file);
+
+        nodes.get_or_alloc(FUSE_ROOT_ID, OsStr::new("other"), node(3));
+        nodes.evict();
+
+        assert!(nodes.get(dir).is_some());
+        assert_eq!(nodes.path(file).unwrap().to_str(), Some("dir/file"));
+    }
+
+    #[test]
+    fn stale_inode_gives_up_its_name() {
+        let mut nodes = InodeTable::new(node(0), 16);
+
+        let dir = nodes.get_or_alloc(FUSE_ROOT_ID, OsStr::new("dir"), node(1));
+        nodes.lookup(dir);
+        nodes.mark_stale(dir);
+
+        let again = nodes.get_or_alloc(FUSE_ROOT_ID, OsStr::new("dir"), node(3));
+        assert_ne!(again, dir);
+        assert!(nodes.get(dir).unwrap().stale);
+        assert!(!nodes.get(again).unwrap().stale);
+
+        // Children found through the old inode are gone as well
+        let file = nodes.get_or_alloc(dir, OsStr::new("file"), node(2));
+        assert!(nodes.get(file).unwrap().stale);
+    }
+
+    #[test]
+    fn detached_inode_keeps_its_node() {
+        let mut nodes = InodeTable::new(node(0), 16);
+
+        let dir = nodes.get_or_alloc(FUSE_ROOT_ID, OsStr::new("dir"), node(1));
+        nodes.lookup(dir);
+        nodes.detach(dir);
+
+        let again = nodes.get_or_alloc(FUSE_ROOT_ID, OsStr::new("dir"), node(3));
+        assert_ne!(again, dir);
+        assert_eq!(nodes.get(dir).unwrap().node, node(1));
+        assert!(!nodes.get(dir).unwrap().stale);
+
+        // Children found through the old inode are detached as well
+        let file = nodes.get_or_alloc(dir, OsStr::new("file"), node(2));
+        assert!(nodes.get(file).unwrap().detached);
+        assert!(!nodes.get(again).unwrap().detached);
+    }
+
+    #[test]
+    fn evicting_stale_inode_keeps_the_new_name() {
+        let mut nodes = InodeTable::new(node(0), 2);
+
+        let old = nodes.get_or_alloc(FUSE_ROOT_ID, OsStr::new("a"), node(1));
+        nodes.mark_stale(old);
+        let new = nodes.get_or_alloc(FUSE_ROOT_ID, OsStr::new("a"), node(2));
+        nodes.lookup(new);
+
+        assert!(nodes.get(old).is_none());
+        assert_eq!(nodes.get_or_alloc(FUSE_ROOT_ID, OsStr::new("a"), node(2)), new);
+    }
+
+    #[test]
+    fn root_is_never_evicted() {
+        let mut nodes = InodeTable::new(node(0), 0);
+
+        let a = nodes.get_or_alloc(FUSE_ROOT_ID, OsStr::new("a"), node(1));
+        nodes.evict();
+
+        assert!(nodes.get(a).is_none());
+        assert!(nodes.get(FUSE_ROOT_ID).is_some());
+    }
+}
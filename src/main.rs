@@ -2,18 +2,33 @@ extern crate fuse;
 extern crate libc;
 extern crate time;
 extern crate git2;
+extern crate getopts;
+
+mod cache;
+mod error;
+mod handles;
+mod history;
+mod info;
+mod inodes;
+mod pool;
+mod refs;
+mod submodules;
+#[cfg(test)]
+mod testutil;
+mod tracker;
+mod trees;
+mod worker;
 
 use std::env;
-use std::path::Path;
-use std::collections::HashMap;
-use std::cmp::max;
+use std::process;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
 
-use libc::ENOENT;
 use time::Timespec;
 
 use fuse::{
-    FileType,
-    FileAttr,
     Filesystem,
     Request,
     ReplyData,
@@ -26,266 +41,188 @@ use fuse::{
     ReplyCreate,
     ReplyLock,
     ReplyBmap,
-    ReplyXTimes,
     ReplyDirectory
 };
 
-use git2::{Repository, Tree, Blob, Object, Oid, TreeEntry, ObjectType};
+#[cfg(target_os = "macos")]
+use fuse::ReplyXTimes;
 
-const TTL: Timespec = Timespec { sec: 1, nsec: 0 };                 // 1 second
+use libc::{c_int, EROFS, O_ACCMODE, O_RDONLY, W_OK};
 
-const CREATE_TIME: Timespec = Timespec { sec: 1381237736, nsec: 0 };    // 2013-10-08 08:56
+use git2::{Commit, Repository};
 
-struct Bimap {
-    forward: Vec<Oid>,
-    reverse: HashMap<Oid, usize>
+use pool::Pool;
+use tracker::Tracker;
+use worker::{State, Times, Worker};
+
+// Byte budget for inflated blobs and parsed trees, unless --cache-size is given
+const DEFAULT_CACHE_SIZE: usize = 256 << 20;
+
+/// Who the files are reported as belonging to.
+#[derive(Clone, Copy)]
+enum Owner {
+    /// Always this (uid, gid)
+    Fixed(u32, u32),
+    /// Whoever is asking, so every user sees the files as their own. The
+    /// kernel caches attributes per inode for the TTL, so two users stat'ing
+    /// the same file within that window can see each other's ids.
+    Caller
 }
 
-impl Bimap {
-    fn new() -> Bimap {
-        Bimap {
-            forward: Vec::new(),
-            reverse: HashMap::new()
-        }
-    }
+/// Hands every request to the worker pool, which replies once it is done.
+/// Only bookkeeping that never touches the repository is done inline.
+struct GitFilesystem {
+    state: Arc<Mutex<State>>,
+    pool: Pool<Worker>,
+    owner: Owner
+}
 
-    fn get_forward(&self, k: u64) -> Option<Oid> {
-        if (k as usize) <= self.forward.len() {
-            Some(self.forward[k as usize - 1])
-        } else {
-            None
+impl GitFilesystem {
+    fn new(repo: &Repository, commit: &Commit, options: &Options) -> GitFilesystem {
+        let state = Arc::new(Mutex::new(State::new(repo, commit, options.cache_size, options.times, options.refs)));
+
+        if options.times == Times::History || options.follow {
+            Tracker {
+                repo_path: repo.path().to_path_buf(),
+                rev: options.rev.clone(),
+                head: commit.id(),
+                times: options.times,
+                follow: options.follow
+            }.spawn(state.clone());
         }
-    }
 
-    fn get_reverse(&self, v: &Oid) -> Option<u64> {
-        match self.reverse.get(v) {
-            Some(&k) => Some(k as u64),
-            None => None
+        let workers = (0..options.threads).map(|_| Worker::new(state.clone())).collect();
+
+        GitFilesystem {
+            state: state,
+            pool: Pool::new(workers),
+            owner: options.owner
         }
     }
 
-    fn get_reverse_or_alloc(&mut self, v: &Oid) -> u64 {
-        match self.get_reverse(v) {
-            Some(k) => return k,
-            None => {}
+    fn get_owner(&self, req: &Request) -> (u32, u32) {
+        match self.owner {
+            Owner::Fixed(uid, gid) => (uid, gid),
+            Owner::Caller => (req.uid(), req.gid())
         }
+    }
+}
 
+impl Filesystem for GitFilesystem {
+    fn destroy (&mut self, _req: &Request) {
+        let (hits, misses) = self.state.lock().unwrap().cache_stats();
+        println!("object cache: {} hits, {} misses", hits, misses);
+    }
 
-        self.forward.push(*v);
-
-        let k = self.forward.len();
-
-        self.reverse.insert(*v, k);
-
-        k as u64
+    fn lookup (&mut self, req: &Request, parent: u64, name: &Path, reply: ReplyEntry) {
+        let name = name.to_path_buf();
+        let owner = self.get_owner(req);
+        self.pool.execute(move |w| w.lookup(parent, &name, owner, reply));
     }
-}
 
-struct GitFilesystem {
-    repo: Repository,
-    nodes: Bimap
-}
+    fn forget (&mut self, _req: &Request, ino: u64, nlookup: u64) {
+        self.state.lock().unwrap().forget(ino, nlookup);
+    }
 
-fn get_tree_entry_info<'repo, 'entry>(
-    nodes: &mut Bimap,
-    entry: &'entry TreeEntry<'repo>) -> (u64, FileType, &'entry str) {
+    fn getattr (&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+        let owner = self.get_owner(req);
+        self.pool.execute(move |w| w.getattr(ino, owner, reply));
+    }
 
-    let kind = match entry.kind().unwrap() {
-        ObjectType::Tree => FileType::Directory,
-        ObjectType::Blob => FileType::RegularFile,
-        t => panic!("unexpected type: {:?}", t)
-    };
+    fn readlink (&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        self.pool.execute(move |w| w.readlink(ino, reply));
+    }
 
-    let name = entry.name().unwrap();
+    fn open (&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        if flags as c_int & O_ACCMODE != O_RDONLY {
+            reply.error(EROFS);
+            return;
+        }
+        self.pool.execute(move |w| w.open(ino, flags, reply));
+    }
 
-    (nodes.get_reverse_or_alloc(&entry.id()), kind, name)
-}
+    fn read (&mut self, _req: &Request, ino: u64, fh: u64, offset: u64, size: u32, reply: ReplyData) {
+        self.pool.execute(move |w| w.read(ino, fh, offset, size, reply));
+    }
 
-fn get_tree<'repo>(repo: &'repo Repository, nodes: &mut Bimap, ino: u64) -> Result<Tree<'repo>, git2::Error> {
-    let oid = match nodes.get_forward(ino) {
-        Some(v) => v,
-        None => return Err(git2::Error::from_str("inode not found"))
-    };
-    repo.find_tree(oid)
-}
+    fn release (&mut self, _req: &Request, _ino: u64, fh: u64, _flags: u32, _lock_owner: u64, _flush: bool, reply: ReplyEmpty) {
+        self.state.lock().unwrap().release(fh);
+        reply.ok();
+    }
 
-fn get_obj<'repo>(repo: &'repo Repository, nodes: &mut Bimap, ino: u64) -> Result<Object<'repo>, git2::Error> {
-    let oid = match nodes.get_forward(ino) {
-        Some(v) => v,
-        None => return Err(git2::Error::from_str("inode not found"))
-    };
-    repo.find_object(oid, None)
-}
+    fn opendir (&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        self.pool.execute(move |w| w.opendir(ino, flags, reply));
+    }
 
-impl GitFilesystem {
-    fn new(repo: Repository, root: Oid) -> GitFilesystem {
-        let mut g = GitFilesystem {
-            repo: repo,
-            nodes: Bimap::new()
-        };
+    fn readdir (&mut self, _req: &Request, ino: u64, fh: u64, offset: u64, reply: ReplyDirectory) {
+        self.pool.execute(move |w| w.readdir(ino, fh, offset, reply));
+    }
 
-        g.nodes.forward.push(root);
-        g.nodes.reverse.insert(root, 1);
+    fn releasedir (&mut self, _req: &Request, _ino: u64, fh: u64, _flags: u32, reply: ReplyEmpty) {
+        self.state.lock().unwrap().releasedir(fh);
+        reply.ok();
+    }
 
-        g
+    fn statfs (&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        self.pool.execute(move |w| w.statfs(reply));
     }
-}
 
-impl Filesystem for GitFilesystem {
-    fn lookup (&mut self, _req: &Request, parent: u64, name: &Path, reply: ReplyEntry) {
-        // println!("lookup {:?} {:?}", parent, name);
-
-        let tree = get_tree(&self.repo, &mut self.nodes, parent);
-
-        match tree {
-             Ok(tree) => {
-                for i in 0..tree.len() {
-                    let entry = tree.get(i).unwrap();
-
-                    if entry.name().unwrap() == name.to_str().unwrap() {
-                        let (ino, kind, name) = get_tree_entry_info(&mut self.nodes, &entry);
-
-                        let obj = self.repo.find_object(entry.id(), None).unwrap();
-
-                        let (kind, size) = if let Some(blob) = obj.as_blob() {
-                            (FileType::RegularFile, blob.content().len())
-                        } else {
-                            match obj.kind().unwrap() {
-                                ObjectType::Tree => (FileType::Directory, 0),
-                                t => panic!("unexpected type: {:?}", t)
-                            }
-                        };
-
-                        let attr = FileAttr {
-                            ino: ino,
-                            size: size as u64,
-                            blocks: (size + 4095) as u64 / 4096,
-                            atime: CREATE_TIME,
-                            mtime: CREATE_TIME,
-                            ctime: CREATE_TIME,
-                            crtime: CREATE_TIME,
-                            kind: kind,
-                            perm: 0o755,
-                            nlink: 2,
-                            uid: 99,
-                            gid: 99,
-                            rdev: 0,
-                            flags: 0,
-                        };
-
-                        // println!("  entry {:?}", attr);
-                        reply.entry(&TTL, &attr, 0);
-                        return;
-                    }
-                }
-            }
-            Err(e) => {
-                println!("error: {:?}", e);
-            }
+    fn access (&mut self, _req: &Request, _ino: u64, mask: u32, reply: ReplyEmpty) {
+        if mask as c_int & W_OK != 0 {
+            reply.error(EROFS);
+        } else {
+            reply.ok();
         }
-
-        reply.error(ENOENT);
     }
 
-    fn getattr (&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        // println!("getattr {:?}", ino);
+    // Everything below would modify the tree, which is never possible
 
-        let obj = match get_obj(&mut self.repo, &mut self.nodes, ino) {
-            Ok(obj) => obj,
-            Err(e) => {
-                panic!("object not found; error: {:?}", e);
-            }
-        };
+    fn setattr (&mut self, _req: &Request, _ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, _size: Option<u64>, _atime: Option<Timespec>, _mtime: Option<Timespec>, _fh: Option<u64>, _crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>, _flags: Option<u32>, reply: ReplyAttr) {
+        reply.error(EROFS);
+    }
 
-        let (kind, size) = if let Some(blob) = obj.as_blob() {
-            (FileType::RegularFile, blob.content().len())
-        } else {
-            match obj.kind().unwrap() {
-                ObjectType::Tree => (FileType::Directory, 0),
-                t => panic!("unexpected type: {:?}", t)
-            }
-        };
-
-        let attr = FileAttr {
-            ino: ino,
-            size: size as u64,
-            blocks: (size + 4095) as u64 / 4096,
-            atime: CREATE_TIME,
-            mtime: CREATE_TIME,
-            ctime: CREATE_TIME,
-            crtime: CREATE_TIME,
-            kind: kind,
-            perm: 0o755,
-            nlink: 2,
-            uid: 99,
-            gid: 99,
-            rdev: 0,
-            flags: 0,
-        };
-
-        // println!("  attr {:?}", attr);
-        reply.attr(&TTL, &attr);
-
-        // match ino {
-        //     1 => reply.attr(&TTL, &HELLO_DIR_ATTR),
-        //     2 => reply.attr(&TTL, &HELLO_TXT_ATTR),
-        //     _ => reply.error(ENOENT),
-        // }
-    }
-
-    fn read (&mut self, _req: &Request, ino: u64, _fh: u64, offset: u64, _size: u32, reply: ReplyData) {
-        // println!("read {:?} {:?} {:?} {:?}", ino, _fh, offset, _size);
-
-        let obj = match get_obj(&mut self.repo, &mut self.nodes, ino) {
-            Ok(obj) => obj,
-            Err(e) => {
-                panic!("object not found; error: {:?}", e);
-            }
-        };
+    fn mknod (&mut self, _req: &Request, _parent: u64, _name: &Path, _mode: u32, _rdev: u32, reply: ReplyEntry) {
+        reply.error(EROFS);
+    }
 
-        if let Some(blob) = obj.as_blob() {
-            reply.data(&blob.content()[offset as usize .. offset as usize + _size as usize]);
-        } else {
-            panic!("unexpected type: {:?}", obj.kind())
-        };
+    fn mkdir (&mut self, _req: &Request, _parent: u64, _name: &Path, _mode: u32, reply: ReplyEntry) {
+        reply.error(EROFS);
+    }
 
+    fn unlink (&mut self, _req: &Request, _parent: u64, _name: &Path, reply: ReplyEmpty) {
+        reply.error(EROFS);
     }
 
-    fn readdir (&mut self, _req: &Request, ino: u64, _fh: u64, offset: u64, mut reply: ReplyDirectory) {
-        // println!("readdir {:?} {:?} {:?}", ino, _fh, offset);
+    fn rmdir (&mut self, _req: &Request, _parent: u64, _name: &Path, reply: ReplyEmpty) {
+        reply.error(EROFS);
+    }
 
-        let tree = get_tree(&mut self.repo, &mut self.nodes, ino);
+    fn symlink (&mut self, _req: &Request, _parent: u64, _name: &Path, _link: &Path, reply: ReplyEntry) {
+        reply.error(EROFS);
+    }
 
-        match tree {
-             Ok(tree) => {
-                if offset != 0 && offset as usize != tree.len() + 1 {
-                    panic!("unexpected offset: {}", offset);
-                }
+    fn rename (&mut self, _req: &Request, _parent: u64, _name: &Path, _newparent: u64, _newname: &Path, reply: ReplyEmpty) {
+        reply.error(EROFS);
+    }
 
-                if offset == 0 {
-                    // println!("  add 1 0 Directory .");
-                    reply.add(1, 0, FileType::Directory, ".");
-                    // println!("  add 1 1 Directory ..");
-                    reply.add(1, 1, FileType::Directory, "..");
+    fn link (&mut self, _req: &Request, _ino: u64, _newparent: u64, _newname: &Path, reply: ReplyEntry) {
+        reply.error(EROFS);
+    }
 
-                    for i in 0..tree.len() {
-                        let entry = tree.get(i).unwrap();
-                        let (ino, kind, name) = get_tree_entry_info(&mut self.nodes, &entry);
+    fn write (&mut self, _req: &Request, _ino: u64, _fh: u64, _offset: u64, _data: &[u8], _flags: u32, reply: ReplyWrite) {
+        reply.error(EROFS);
+    }
 
-                        // println!("  add {} {} {:?} {}", ino, i + 2, kind, name);
-                        reply.add(ino, i as u64 + 2, kind, name);
-                    }
-                }
+    fn create (&mut self, _req: &Request, _parent: u64, _name: &Path, _mode: u32, _flags: u32, reply: ReplyCreate) {
+        reply.error(EROFS);
+    }
 
-                reply.ok();
-                return;
-            }
-            Err(e) => {
-                println!("error: {:?}", e);
-            }
-        }
+    fn setxattr (&mut self, _req: &Request, _ino: u64, _name: &OsStr, _value: &[u8], _flags: u32, _position: u32, reply: ReplyEmpty) {
+        reply.error(EROFS);
+    }
 
-        reply.error(ENOENT);
+    fn removexattr (&mut self, _req: &Request, _ino: u64, _name: &OsStr, reply: ReplyEmpty) {
+        reply.error(EROFS);
     }
 }
 
@@ -612,17 +549,192 @@ impl<T: Filesystem> Filesystem for LoggingFilesystem<T> {
     }
 }
 
+struct Options {
+    repo: PathBuf,
+    rev: String,
+    cache_size: usize,
+    threads: usize,
+    times: Times,
+    owner: Owner,
+    refs: bool,
+    follow: bool,
+    mount_options: Vec<String>,
+    mountpoint: OsString
+}
+
+fn print_usage(program: &str, opts: &getopts::Options) {
+    let brief = format!("Usage: {} [--repo PATH] [--rev REVSPEC] [-o OPTIONS] MOUNTPOINT", program);
+    print!("{}", opts.usage(&brief));
+}
+
+fn parse_options() -> Options {
+    let program = env::args_os().next().map(|p| p.to_string_lossy().into_owned()).unwrap_or_default();
+
+    let mut opts = getopts::Options::new();
+    opts.optopt("", "repo", "path to the git repository (default: .)", "PATH");
+    opts.optopt("", "rev", "revision to mount, in any form accepted by git rev-parse (default: HEAD); its commit metadata is in .commit/ at the root, which is not listed", "REVSPEC");
+    opts.optopt("", "cache-size", "memory for cached blobs and trees, in MiB (default: 256)", "MIB");
+    opts.optopt("", "threads", "number of threads serving requests (default: one per CPU)", "N");
+    opts.optopt("", "times", "where file times come from: commit (the mounted commit) or history (the commits that last changed and first added each path) (default: commit)", "MODE");
+    opts.optflag("", "refs", "show every branch, tag and remote branch under the root, plus HEAD and commits/ for looking up any commit, instead of a single revision; each has its commit metadata in a <name>.commit/ next to it");
+    opts.optflag("", "follow", "keep following --rev as it moves, showing whatever it currently points at");
+    opts.optopt("", "uid", "user id that owns every file (default: the mounting user)", "UID");
+    opts.optopt("", "gid", "group id that owns every file (default: the mounting user's group)", "GID");
+    opts.optflag("", "caller-owns", "report every file as owned by whoever accesses it");
+    opts.optmulti("o", "", "comma-separated mount options passed through to fuse; ro is added unless rw is given", "OPTIONS");
+    opts.optflag("h", "help", "print this help message");
+
+    // env::args() would panic on these; getopts cannot take them either
+    let args: Vec<String> = match env::args_os().map(|arg| arg.into_string()).collect() {
+        Ok(args) => args,
+        Err(arg) => {
+            println!("invalid argument {:?}: not valid UTF-8", arg);
+            print_usage(&program, &opts);
+            process::exit(1);
+        }
+    };
+
+    let matches = match opts.parse(args.iter().skip(1)) {
+        Ok(m) => m,
+        Err(e) => {
+            println!("{}", e);
+            print_usage(&program, &opts);
+            process::exit(1);
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(&program, &opts);
+        process::exit(0);
+    }
+
+    if matches.free.len() != 1 {
+        print_usage(&program, &opts);
+        process::exit(1);
+    }
+
+    let cache_size = match matches.opt_str("cache-size").map(|s| s.parse::<usize>()) {
+        None => DEFAULT_CACHE_SIZE,
+        Some(Ok(mib)) => match mib.checked_mul(1 << 20) {
+            Some(bytes) => bytes,
+            None => {
+                println!("invalid --cache-size: {} MiB is too large", mib);
+                process::exit(1);
+            }
+        },
+        Some(Err(e)) => {
+            println!("invalid --cache-size: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let threads = match matches.opt_str("threads").map(|s| s.parse::<usize>()) {
+        None => thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        Some(Ok(n)) if n > 0 => n,
+        Some(Ok(_)) => {
+            println!("invalid --threads: must be at least 1");
+            process::exit(1);
+        }
+        Some(Err(e)) => {
+            println!("invalid --threads: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let times = match matches.opt_str("times").as_ref().map(|s| &s[..]) {
+        None | Some("commit") => Times::Commit,
+        Some("history") => Times::History,
+        Some(other) => {
+            println!("invalid --times: {} (expected commit or history)", other);
+            process::exit(1);
+        }
+    };
+
+    let refs = matches.opt_present("refs");
+    if refs && times == Times::History {
+        println!("--times history cannot be combined with --refs");
+        process::exit(1);
+    }
+
+    let follow = matches.opt_present("follow");
+    if follow && refs {
+        println!("--follow cannot be combined with --refs, which always shows refs as they are now");
+        process::exit(1);
+    }
+
+    let uid = match matches.opt_str("uid").map(|s| s.parse::<u32>()) {
+        None => unsafe { libc::getuid() },
+        Some(Ok(uid)) => uid,
+        Some(Err(e)) => {
+            println!("invalid --uid: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let gid = match matches.opt_str("gid").map(|s| s.parse::<u32>()) {
+        None => unsafe { libc::getgid() },
+        Some(Ok(gid)) => gid,
+        Some(Err(e)) => {
+            println!("invalid --gid: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let owner = if matches.opt_present("caller-owns") {
+        if matches.opt_present("uid") || matches.opt_present("gid") {
+            println!("--caller-owns cannot be combined with --uid or --gid");
+            process::exit(1);
+        }
+        Owner::Caller
+    } else {
+        Owner::Fixed(uid, gid)
+    };
+
+    Options {
+        repo: PathBuf::from(matches.opt_str("repo").unwrap_or(".".to_string())),
+        rev: matches.opt_str("rev").unwrap_or("HEAD".to_string()),
+        cache_size: cache_size,
+        threads: threads,
+        times: times,
+        owner: owner,
+        refs: refs,
+        follow: follow,
+        mount_options: matches.opt_strs("o"),
+        mountpoint: OsString::from(&matches.free[0])
+    }
+}
+
 fn main () {
+    let options = parse_options();
 
-    let repo = match Repository::open(".") {
+    let repo = match Repository::discover(&options.repo) {
         Ok(repo) => repo,
-        Err(e) => panic!("failed to open: {}", e),
+        Err(e) => {
+            println!("failed to open {}: {}", options.repo.display(), e);
+            process::exit(1);
+        }
     };
 
-    let master = repo.revparse_single("master").unwrap().id();
+    let commit = match tracker::resolve(&repo, &options.rev) {
+        Ok(commit) => commit,
+        Err(e) => {
+            println!("failed to resolve {}: {}", options.rev, e);
+            process::exit(1);
+        }
+    };
 
-    let tree = repo.find_commit(master).unwrap().tree().unwrap().id();
+    let mut mount_args: Vec<&OsStr> = Vec::new();
+
+    // Let the kernel refuse writes up front, unless asked otherwise
+    let rw = options.mount_options.iter().any(|o| o.split(',').any(|opt| opt == "rw"));
+    if !rw {
+        mount_args.push(OsStr::new("-o"));
+        mount_args.push(OsStr::new("ro"));
+    }
+    for o in options.mount_options.iter() {
+        mount_args.push(OsStr::new("-o"));
+        mount_args.push(OsStr::new(o));
+    }
 
-    let mountpoint = env::args_os().nth(1).unwrap();
-    fuse::mount(LoggingFilesystem::new(GitFilesystem::new(repo, tree)), &mountpoint, &[]);
+    fuse::mount(LoggingFilesystem::new(GitFilesystem::new(&repo, &commit, &options)), &options.mountpoint, &mount_args);
 }
@@ -0,0 +1,52 @@
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender};
+use std::thread::{self, JoinHandle};
+
+type Job<W> = Box<dyn FnOnce(&mut W) + Send>;
+
+/// A fixed set of threads, each owning one worker, taking jobs from a shared
+/// queue. Jobs run on whichever thread is free first.
+pub struct Pool<W> {
+    jobs: Option<Sender<Job<W>>>,
+    threads: Vec<JoinHandle<()>>
+}
+
+impl<W: Send + 'static> Pool<W> {
+    pub fn new(workers: Vec<W>) -> Pool<W> {
+        let (sender, receiver) = channel::<Job<W>>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let threads = workers.into_iter().map(|mut worker| {
+            let receiver = receiver.clone();
+            thread::spawn(move || {
+                loop {
+                    // The lock is only held while waiting for the next job
+                    let job = match receiver.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => break
+                    };
+                    job(&mut worker);
+                }
+            })
+        }).collect();
+
+        Pool {
+            jobs: Some(sender),
+            threads: threads
+        }
+    }
+
+    pub fn execute<F: FnOnce(&mut W) + Send + 'static>(&self, job: F) {
+        self.jobs.as_ref().unwrap().send(Box::new(job)).unwrap();
+    }
+}
+
+impl<W> Drop for Pool<W> {
+    fn drop(&mut self) {
+        // Closing the queue lets every thread finish its current job and exit
+        self.jobs.take();
+        for t in self.threads.drain(..) {
+            t.join().unwrap();
+        }
+    }
+}
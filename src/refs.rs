@@ -0,0 +1,161 @@
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+
+use git2::{ObjectType, Oid, Repository};
+
+use error::{self, Error};
+use info::{self, MODE_COMMIT_INFO};
+use trees::{CachedEntry, CachedTree};
+
+/// A level of ref names, like `branches/` or `branches/feature/`. Git never
+/// writes this mode, so it cannot clash with a real tree entry.
+pub const MODE_REFS: i32 = 0o040001;
+
+/// A single ref, showing the tree of the commit it points at.
+pub const MODE_REF: i32 = 0o040002;
+
+/// The `commits/` directory, which lists nothing but can look up any commit.
+pub const MODE_COMMITS: i32 = 0o040003;
+
+/// The tree of the commit in `Node::oid`, as found in `commits/`.
+pub const MODE_COMMIT_DIR: i32 = 0o040004;
+
+/// The metadata directory next to a ref, `<ref>.commit`, showing the commit
+/// the ref points at.
+pub const MODE_REF_INFO: i32 = 0o040007;
+
+// Top-level directories of the view and the ref namespaces they show
+const NAMESPACES: &[(&str, &str)] = &[
+    ("branches", "refs/heads/"),
+    ("tags", "refs/tags/"),
+    ("remotes", "refs/remotes/")
+];
+
+fn entry(name: &str, mode: i32) -> CachedEntry {
+    CachedEntry {
+        name: OsString::from(name),
+        oid: Oid::zero(),
+        mode: mode
+    }
+}
+
+/// The full ref name that a path in the view stands for. For a level of
+/// ref names this is the prefix shared by everything below it.
+fn ref_name(path: &Path) -> error::Result<String> {
+    let mut parts = Vec::new();
+    for c in path.iter() {
+        parts.push(c.to_str().ok_or(Error::NotFound)?);
+    }
+
+    if parts == ["HEAD"] {
+        return Ok("HEAD".to_string());
+    }
+
+    let prefix = match parts.first().and_then(|top| NAMESPACES.iter().find(|ns| ns.0 == *top)) {
+        Some(&(_, prefix)) => prefix,
+        None => return Err(Error::NotFound)
+    };
+
+    Ok(format!("{}{}", prefix, parts[1..].join("/")))
+}
+
+/// The entries of the view at `path`, a `MODE_REFS` directory. Refs are
+/// listed afresh every time, so branches come and go as they are created
+/// and deleted.
+pub fn list(repo: &Repository, path: &Path) -> error::Result<CachedTree> {
+    if path.as_os_str().is_empty() {
+        let mut entries: Vec<CachedEntry> = NAMESPACES.iter().map(|ns| entry(ns.0, MODE_REFS)).collect();
+        entries.push(entry("commits", MODE_COMMITS));
+        // An unborn HEAD has nothing to show
+        if repo.head().is_ok() {
+            entries.push(entry("HEAD", MODE_REF));
+            entries.push(entry(&format!("HEAD{}", info::SUFFIX), MODE_REF_INFO));
+        }
+        return Ok(CachedTree::from_entries(entries));
+    }
+
+    let mut prefix = ref_name(path)?;
+    if !prefix.ends_with('/') {
+        prefix.push('/');
+    }
+
+    let mut children = BTreeMap::new();
+    for name in repo.references()?.names() {
+        let name = name?;
+        if !name.starts_with(&prefix) {
+            continue;
+        }
+
+        // A ref that really is called `<name>.commit` wins over the
+        // metadata directory of `<name>`
+        let rest = &name[prefix.len()..];
+        match rest.find('/') {
+            Some(i) => children.insert(rest[..i].to_string(), MODE_REFS),
+            None => {
+                children.entry(format!("{}{}", rest, info::SUFFIX)).or_insert(MODE_REF_INFO);
+                children.insert(rest.to_string(), MODE_REF)
+            }
+        };
+    }
+
+    Ok(CachedTree::from_entries(children.iter().map(|(name, &mode)| entry(name, mode)).collect()))
+}
+
+/// The tree shown for the `MODE_REF` directory at `path`, looked up anew
+/// on every call so it follows the ref as it moves.
+pub fn resolve(repo: &Repository, path: &Path) -> error::Result<Oid> {
+    let name = ref_name(path)?;
+    Ok(repo.find_reference(&name)?.peel_to_tree()?.id())
+}
+
+/// The commit the `MODE_REF` directory at `path` stands for, if the ref
+/// leads to one; a tag can also point straight at a tree.
+pub fn resolve_commit(repo: &Repository, path: &Path) -> error::Result<Option<Oid>> {
+    let name = ref_name(path)?;
+    Ok(repo.find_reference(&name)?.peel_to_commit().ok().map(|commit| commit.id()))
+}
+
+/// The commit shown by the `MODE_REF_INFO` directory at `path`, looked up
+/// anew on every call like `resolve`.
+pub fn resolve_info(repo: &Repository, path: &Path) -> error::Result<Oid> {
+    let name = path.file_name().and_then(|name| name.to_str()).ok_or(Error::NotFound)?;
+    let ref_path = path.with_file_name(name.strip_suffix(info::SUFFIX).ok_or(Error::NotFound)?);
+
+    // A ref that leads to a tree has no commit to describe
+    match resolve_commit(repo, &ref_path)? {
+        Some(commit) => Ok(commit),
+        None => Err(Error::NotFound)
+    }
+}
+
+/// Look up `name` in `commits/`. Any revspec that names a commit works,
+/// most usefully a full or abbreviated SHA; the whole history is far too
+/// big to list, so nothing is ever shown by readdir. Each commit also has a
+/// metadata directory next to it, named with `info::SUFFIX`.
+pub fn lookup_commit(repo: &Repository, name: &OsStr) -> error::Result<CachedEntry> {
+    let name_str = name.to_str().ok_or(Error::NotFound)?;
+
+    // `<spec>.commit` is the metadata directory of `<spec>`
+    let (spec, mode) = match name_str.strip_suffix(info::SUFFIX) {
+        Some(spec) => (spec, MODE_COMMIT_INFO),
+        None => (name_str, MODE_COMMIT_DIR)
+    };
+
+    // Unknown, ambiguous and malformed names alike simply do not exist
+    let commit = match repo.revparse_single(spec).and_then(|obj| obj.peel(ObjectType::Commit)) {
+        Ok(commit) => commit,
+        Err(_) => return Err(Error::NotFound)
+    };
+
+    Ok(CachedEntry {
+        name: name.to_os_string(),
+        oid: commit.id(),
+        mode: mode
+    })
+}
+
+/// The tree of the commit behind a `MODE_COMMIT_DIR` directory.
+pub fn commit_tree(repo: &Repository, commit: Oid) -> error::Result<Oid> {
+    Ok(repo.find_commit(commit)?.tree_id())
+}
@@ -0,0 +1,60 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use git2::Repository;
+
+/// Open every submodule repository that is available locally for `repo`:
+/// the ones git keeps under `.git/modules/<name>` and the ones checked out
+/// at their configured paths in the working directory.
+pub fn open_submodule_repos(repo: &Repository) -> Vec<Repository> {
+    let mut found = Vec::new();
+
+    scan_modules_dir(&repo.path().join("modules"), &mut found);
+
+    if let Ok(submodules) = repo.submodules() {
+        for sm in submodules.iter() {
+            if let Ok(sub) = sm.open() {
+                found.push(sub);
+            }
+        }
+    }
+
+    // A checked-out submodule usually points back into .git/modules, so the
+    // same repository can turn up from both sources.
+    let mut seen: Vec<PathBuf> = Vec::new();
+    found.into_iter().filter(|r| {
+        let path = fs::canonicalize(r.path()).unwrap_or(r.path().to_path_buf());
+        if seen.contains(&path) {
+            false
+        } else {
+            seen.push(path);
+            true
+        }
+    }).collect()
+}
+
+// Submodule names may contain slashes, so look through nested directories
+// until something opens as a repository. Anything below a repository belongs
+// to that repository's own submodules and is picked up when it is resolved.
+fn scan_modules_dir(dir: &Path, found: &mut Vec<Repository>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return
+    };
+
+    for entry in entries {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(_) => continue
+        };
+
+        if !path.is_dir() {
+            continue;
+        }
+
+        match Repository::open(&path) {
+            Ok(repo) => found.push(repo),
+            Err(_) => scan_modules_dir(&path, found)
+        }
+    }
+}
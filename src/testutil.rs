@@ -0,0 +1,63 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+use git2::{Oid, Repository, Signature, Time};
+
+/// A bare repository in the temporary directory, removed again on drop.
+pub struct TestRepo {
+    pub path: PathBuf,
+    pub repo: Repository
+}
+
+impl TestRepo {
+    pub fn new(name: &str) -> TestRepo {
+        let path = env::temp_dir().join(format!("git-fs-test-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&path);
+
+        TestRepo {
+            repo: Repository::init_bare(&path).unwrap(),
+            path: path
+        }
+    }
+
+    /// Commit `files`, given as (path, content) with `/` between
+    /// directories, on top of `parent` at `time`.
+    pub fn commit(&self, parent: Option<Oid>, time: i64, files: &[(&str, &str)]) -> Oid {
+        let tree = self.repo.find_tree(self.write_tree(files)).unwrap();
+        let sig = Signature::new("Test", "test@example.com", &Time::new(time, 0)).unwrap();
+        let parents: Vec<_> = parent.iter().map(|&p| self.repo.find_commit(p).unwrap()).collect();
+        let parents: Vec<_> = parents.iter().collect();
+
+        self.repo.commit(None, &sig, &sig, &format!("commit at {}", time), &tree, &parents).unwrap()
+    }
+
+    fn write_tree(&self, files: &[(&str, &str)]) -> Oid {
+        let mut dirs: BTreeMap<&str, Vec<(&str, &str)>> = BTreeMap::new();
+        let mut builder = self.repo.treebuilder(None).unwrap();
+
+        for &(path, content) in files {
+            match path.find('/') {
+                Some(i) => dirs.entry(&path[..i]).or_default().push((&path[i + 1..], content)),
+                None => {
+                    let blob = self.repo.blob(content.as_bytes()).unwrap();
+                    builder.insert(path, blob, 0o100644).unwrap();
+                }
+            }
+        }
+
+        for (name, files) in dirs {
+            builder.insert(name, self.write_tree(&files), 0o040000).unwrap();
+        }
+
+        builder.write().unwrap()
+    }
+}
+
+impl Drop for TestRepo {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
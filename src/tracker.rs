@@ -0,0 +1,95 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use git2::{self, Commit, ObjectType, Oid, Repository};
+
+use history::PathIndex;
+use worker::{State, Times, Worker};
+
+// How often a followed revision is resolved again to see if it moved
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The commit `rev` names, in any form `git rev-parse` accepts.
+pub fn resolve<'r>(repo: &'r Repository, rev: &str) -> Result<Commit<'r>, git2::Error> {
+    repo.revparse_single(rev)
+        .and_then(|obj| obj.peel(ObjectType::Commit))
+        .and_then(|obj| repo.find_commit(obj.id()))
+}
+
+/// Keeps the mount in step with its revision: builds the history index
+/// when times come from history, and with `follow` polls `rev`, swapping in
+/// the new root tree whenever it moves.
+pub struct Tracker {
+    pub repo_path: PathBuf,
+    pub rev: String,
+    pub head: Oid,
+    pub times: Times,
+    pub follow: bool
+}
+
+impl Tracker {
+    /// Run on a thread of its own for the life of the mount.
+    pub fn spawn(self, state: Arc<Mutex<State>>) {
+        thread::spawn(move || self.run(state));
+    }
+
+    fn run(mut self, state: Arc<Mutex<State>>) {
+        let repo = match Repository::open(&self.repo_path) {
+            Ok(repo) => repo,
+            Err(e) => {
+                println!("failed to open {}: {}", self.repo_path.display(), e);
+                return;
+            }
+        };
+
+        // Requests are served with the commit's time until this is done
+        self.index_history(&repo, &state);
+
+        // New trees are resolved with repositories of this thread's own
+        let mut worker = Worker::new(state.clone());
+
+        while self.follow {
+            thread::sleep(POLL_INTERVAL);
+
+            let commit = match resolve(&repo, &self.rev) {
+                Ok(commit) => commit,
+                Err(e) => {
+                    println!("failed to resolve {}: {}", self.rev, e);
+                    continue;
+                }
+            };
+
+            if commit.id() == self.head {
+                continue;
+            }
+
+            println!("{} moved to {}", self.rev, commit.id());
+
+            // Every known inode moves to the new tree at once. The kernel
+            // may still answer from attributes it cached before, for at most
+            // the TTL, since fuse 0.2 cannot tell it to drop them.
+            worker.set_commit(&commit);
+            self.head = commit.id();
+
+            // Until the index catches up, times of the previous commit are
+            // reported for paths it knows
+            self.index_history(&repo, &state);
+        }
+    }
+
+    fn index_history(&self, repo: &Repository, state: &Mutex<State>) {
+        if self.times != Times::History {
+            return;
+        }
+
+        match PathIndex::open(repo, &self.rev, self.head) {
+            Ok(index) => {
+                println!("history index ready: {} paths", index.len());
+                state.lock().unwrap().set_history(index);
+            }
+            Err(e) => println!("failed to index history: {}", e)
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+
+use git2::{Oid, Tree};
+
+#[derive(Clone)]
+pub struct CachedEntry {
+    pub name: OsString,
+    pub oid: Oid,
+    pub mode: i32
+}
+
+/// The entries of a tree, parsed once, with an index by name.
+pub struct CachedTree {
+    pub entries: Vec<CachedEntry>,
+    // Entries shown as directories, counting gitlinks, for link counts
+    pub subdirs: usize,
+    by_name: HashMap<OsString, usize>
+}
+
+impl CachedTree {
+    pub fn new(tree: &Tree) -> CachedTree {
+        CachedTree::from_entries(tree.iter().map(|entry| CachedEntry {
+            name: OsStr::from_bytes(entry.name_bytes()).to_os_string(),
+            oid: entry.id(),
+            mode: entry.filemode()
+        }).collect())
+    }
+
+    /// A listing that does not come from a git tree.
+    pub fn from_entries(entries: Vec<CachedEntry>) -> CachedTree {
+        let by_name = entries.iter().enumerate().map(|(i, e)| (e.name.clone(), i)).collect();
+
+        // Everything the refs view makes up is typed as a tree too
+        let subdirs = entries.iter().filter(|e| matches!(e.mode & 0o170000, 0o040000 | 0o160000)).count();
+
+        CachedTree {
+            entries: entries,
+            subdirs: subdirs,
+            by_name: by_name
+        }
+    }
+
+    pub fn get(&self, name: &OsStr) -> Option<&CachedEntry> {
+        self.by_name.get(name).map(|&i| &self.entries[i])
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Rough number of bytes this tree occupies, for the cache budget. Each
+    /// name is stored twice: once in the entry and once in the index.
+    pub fn weight(&self) -> usize {
+        self.entries.iter().map(|e| {
+            2 * e.name.len() + size_of::<CachedEntry>() + size_of::<(OsString, usize)>()
+        }).sum()
+    }
+}
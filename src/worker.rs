@@ -0,0 +1,1300 @@
+use std::collections::{HashMap, HashSet};
+use std::cmp::min;
+use std::ffi::{OsStr, OsString};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use time::Timespec;
+
+use fuse::{
+    FileType,
+    FileAttr,
+    ReplyData,
+    ReplyEntry,
+    ReplyAttr,
+    ReplyOpen,
+    ReplyStatfs,
+    ReplyDirectory,
+    FUSE_ROOT_ID
+};
+
+use git2::{Commit, Repository, Oid, ObjectType, TreeWalkMode, TreeWalkResult};
+
+use cache::ObjectCache;
+use error::{self, Error};
+use handles::Handles;
+use history::{Change, PathIndex};
+use info::{self, MODE_COMMIT_INFO, MODE_PARENTS, MODE_INFO_FILE, MODE_PARENT_LINK, MODE_PARENT_DIRS};
+use inodes::{InodeTable, Node};
+use refs::{self, MODE_REFS, MODE_REF, MODE_COMMITS, MODE_COMMIT_DIR, MODE_REF_INFO};
+use submodules;
+use trees::{CachedEntry, CachedTree};
+
+const TTL: Timespec = Timespec { sec: 1, nsec: 0 };                 // 1 second
+
+// Inodes kept around once nothing refers to them any more; this bounds the
+// memory of a long-running mount
+const MAX_INODES: usize = 65536;
+
+// Blob sizes remembered from object headers
+const MAX_CACHED_SIZES: usize = 1 << 20;
+
+// Unit of st_blocks and of the statfs block counts
+const BLOCK_SIZE: u64 = 512;
+
+// Longest file name statfs admits to; git itself has no limit
+const MAX_NAME_LEN: u32 = 255;
+
+// Tree entry filemodes, as stored by git
+const MODE_TREE: i32 = 0o040000;
+const MODE_LINK: i32 = 0o120000;
+const MODE_COMMIT: i32 = 0o160000;
+
+/// Where the times reported for each node come from.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Times {
+    /// Everything carries the committer time of the mounted commit
+    Commit,
+    /// Each path is modified at the commit that last changed it and created
+    /// at the one that added it, once the history index has been built
+    History
+}
+
+/// An open file. The blob is inflated on the first read and kept until the
+/// file is released, so reading it in chunks does not inflate it again for
+/// every chunk. The node is fixed when the file is opened, so it keeps its
+/// content even if the mounted revision moves on in the meantime.
+struct OpenFile {
+    node: Node,
+    content: Option<Arc<Vec<u8>>>
+}
+
+/// An open directory, listed once at `opendir` so that every `readdir` on
+/// the handle sees the same entries, whatever happens to the revision.
+struct OpenDir {
+    repo: usize,
+    tree: Arc<CachedTree>
+}
+
+/// An inode as `Worker::set_commit` found it.
+struct Snapshot {
+    ino: u64,
+    parent: u64,
+    name: OsString,
+    node: Node
+}
+
+/// Totals over the whole mounted tree, for statfs.
+#[derive(Clone, Copy)]
+struct Usage {
+    blocks: u64,
+    files: u64
+}
+
+/// Everything the workers share. Repositories are not shared: each worker
+/// opens its own handle for every path in `repo_paths`, so no lock is held
+/// while libgit2 inflates objects or resolves deltas.
+pub struct State {
+    repo_paths: Vec<PathBuf>,
+    commit: Oid,
+    commit_time: Timespec,
+    times: Times,
+    history: Option<PathIndex>,
+    // Root tree the usage was counted for, and the usage itself
+    usage: Option<(Oid, Usage)>,
+    nodes: InodeTable,
+    objects: ObjectCache,
+    sizes: HashMap<Oid, u64>,
+    files: Handles<OpenFile>,
+    dirs: Handles<OpenDir>,
+    // Submodule repositories found for each entry of `repo_paths`
+    submodules: HashMap<usize, Vec<usize>>,
+    // (repo, commit) of a gitlink -> (repo, tree) it resolved to, if any
+    gitlinks: HashMap<(usize, Oid), Option<(usize, Oid)>>
+}
+
+impl State {
+    /// With `refs`, the root shows every branch and tag instead of the tree
+    /// of `commit`.
+    pub fn new(repo: &Repository, commit: &Commit, cache_size: usize, times: Times, refs: bool) -> State {
+        let root = if refs {
+            Node { repo: 0, oid: Oid::zero(), mode: MODE_REFS }
+        } else {
+            Node { repo: 0, oid: commit.tree_id(), mode: MODE_TREE }
+        };
+
+        State {
+            repo_paths: vec![repo.path().to_path_buf()],
+            commit: commit.id(),
+            commit_time: get_commit_time(commit),
+            times: times,
+            history: None,
+            usage: None,
+            nodes: InodeTable::new(root, MAX_INODES),
+            objects: ObjectCache::new(cache_size),
+            sizes: HashMap::new(),
+            files: Handles::new(),
+            dirs: Handles::new(),
+            submodules: HashMap::new(),
+            gitlinks: HashMap::new()
+        }
+    }
+
+    pub fn forget(&mut self, ino: u64, nlookup: u64) {
+        self.nodes.forget(ino, nlookup);
+    }
+
+    pub fn release(&mut self, fh: u64) {
+        self.files.remove(fh);
+    }
+
+    pub fn releasedir(&mut self, fh: u64) {
+        self.dirs.remove(fh);
+    }
+
+    pub fn set_history(&mut self, index: PathIndex) {
+        self.history = Some(index);
+    }
+
+    pub fn cache_stats(&self) -> (u64, u64) {
+        (self.objects.hits, self.objects.misses)
+    }
+}
+
+fn get_commit_time(commit: &Commit) -> Timespec {
+    Timespec { sec: commit.time().seconds(), nsec: 0 }
+}
+
+fn get_change_time(change: &Change) -> Timespec {
+    Timespec { sec: change.time, nsec: 0 }
+}
+
+fn is_dir(node: &Node) -> bool {
+    matches!(get_file_type(node), Ok(FileType::Directory))
+}
+
+fn get_file_type(node: &Node) -> error::Result<FileType> {
+    match node.mode & 0o170000 {
+        0o040000 | 0o160000 => Ok(FileType::Directory),
+        0o100000 => Ok(FileType::RegularFile),
+        0o120000 => Ok(FileType::Symlink),
+        _ => Err(Error::UnexpectedMode(node.mode))
+    }
+}
+
+/// Permission bits for a node. The mount is read-only, so only the read and
+/// execute bits that git records are ever reported.
+fn get_perm(node: &Node) -> u16 {
+    match node.mode & 0o170000 {
+        0o040000 | 0o160000 => 0o555,
+        0o120000 => 0o777,
+        _ if node.mode & 0o111 != 0 => 0o555,
+        _ => 0o444
+    }
+}
+
+/// The part of a `len`-byte file covered by a read of `size` bytes at
+/// `offset`. Reads that run past the end are cut short, and reads that start
+/// at or beyond it are empty, which is how the kernel recognizes EOF.
+fn read_range(len: usize, offset: u64, size: u32) -> Range<usize> {
+    if offset >= len as u64 {
+        return len..len;
+    }
+
+    let start = offset as usize;
+    let end = start + min(size as usize, len - start);
+
+    start..end
+}
+
+fn get_entry_node(repo: usize, entry: &CachedEntry) -> Node {
+    Node {
+        repo: repo,
+        oid: entry.oid,
+        mode: entry.mode
+    }
+}
+
+/// The inode for `entry` in `parent` and its type. An inode that already
+/// exists keeps its node.
+fn get_tree_entry_info(
+    nodes: &mut InodeTable,
+    parent: u64,
+    repo: usize,
+    entry: &CachedEntry) -> error::Result<(u64, FileType)> {
+
+    let node = get_entry_node(repo, entry);
+
+    let kind = get_file_type(&node)?;
+
+    Ok((nodes.get_or_alloc(parent, &entry.name, node), kind))
+}
+
+fn get_node(nodes: &InodeTable, ino: u64) -> error::Result<Node> {
+    match nodes.get(ino) {
+        Some(inode) if inode.stale => Err(Error::StaleInode(ino)),
+        Some(inode) => Ok(inode.node),
+        None => Err(Error::UnknownInode(ino))
+    }
+}
+
+fn get_node_parent(nodes: &InodeTable, ino: u64) -> error::Result<u64> {
+    match nodes.get(ino) {
+        Some(inode) => Ok(inode.parent),
+        None => Err(Error::UnknownInode(ino))
+    }
+}
+
+fn get_attr(ino: u64, node: &Node, size: u64, nlink: u32, (mtime, crtime): (Timespec, Timespec), (uid, gid): (u32, u32)) -> error::Result<FileAttr> {
+    let kind = get_file_type(node)?;
+
+    Ok(FileAttr {
+        ino: ino,
+        size: size,
+        // st_blocks counts 512-byte units, whatever the block size
+        blocks: size.div_ceil(BLOCK_SIZE),
+        atime: mtime,
+        mtime: mtime,
+        ctime: mtime,
+        crtime: crtime,
+        kind: kind,
+        perm: get_perm(node),
+        nlink: nlink,
+        uid: uid,
+        gid: gid,
+        rdev: 0,
+        flags: 0,
+    })
+}
+
+/// Services requests on one thread of the pool.
+pub struct Worker {
+    state: Arc<Mutex<State>>,
+    repos: Vec<Option<Repository>>
+}
+
+impl Worker {
+    pub fn new(state: Arc<Mutex<State>>) -> Worker {
+        Worker {
+            state: state,
+            repos: Vec::new()
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// This worker's handle on repository `i`, opened on first use.
+    fn repo(&mut self, i: usize) -> error::Result<&Repository> {
+        if self.repos.len() <= i {
+            self.repos.resize_with(i + 1, || None);
+        }
+
+        if self.repos[i].is_none() {
+            let path = self.state().repo_paths[i].clone();
+            self.repos[i] = Some(Repository::open(path)?);
+        }
+
+        Ok(self.repos[i].as_ref().unwrap())
+    }
+
+    fn get_path(&self, ino: u64) -> error::Result<PathBuf> {
+        match self.state().nodes.path(ino) {
+            Some(path) => Ok(path),
+            None => Err(Error::UnknownInode(ino))
+        }
+    }
+
+    /// The entries of a directory inode and the repository they live in.
+    fn get_dir(&mut self, ino: u64) -> error::Result<(usize, Arc<CachedTree>)> {
+        let node = get_node(&self.state().nodes, ino)?;
+        match self.list_dir(ino, &node)? {
+            Some(dir) => Ok(dir),
+            None => Err(Error::NotADirectory(ino))
+        }
+    }
+
+    /// Like `get_dir`, giving None for anything that is not a directory.
+    /// Gitlinks whose submodule is not available locally are shown as empty
+    /// directories.
+    fn list_dir(&mut self, ino: u64, node: &Node) -> error::Result<Option<(usize, Arc<CachedTree>)>> {
+        match node.mode {
+            MODE_TREE => Ok(Some((node.repo, self.get_tree(node.repo, node.oid)?))),
+            MODE_COMMIT => match self.resolve_gitlink(node.repo, node.oid)? {
+                Some((repo, oid)) => Ok(Some((repo, self.get_tree(repo, oid)?))),
+                None => Ok(Some((node.repo, Arc::new(CachedTree::from_entries(Vec::new())))))
+            },
+            MODE_REFS => {
+                let path = self.get_path(ino)?;
+                let listing = refs::list(self.repo(node.repo)?, &path)?;
+                Ok(Some((node.repo, Arc::new(listing))))
+            }
+            MODE_REF => {
+                let path = self.get_path(ino)?;
+                let tree = refs::resolve(self.repo(node.repo)?, &path)?;
+                Ok(Some((node.repo, self.get_tree(node.repo, tree)?)))
+            }
+            MODE_COMMITS => Ok(Some((node.repo, Arc::new(CachedTree::from_entries(Vec::new()))))),
+            MODE_COMMIT_DIR => {
+                let tree = refs::commit_tree(self.repo(node.repo)?, node.oid)?;
+                Ok(Some((node.repo, self.get_tree(node.repo, tree)?)))
+            }
+            MODE_REF_INFO => {
+                let path = self.get_path(ino)?;
+                let commit = refs::resolve_info(self.repo(node.repo)?, &path)?;
+                Ok(Some((node.repo, Arc::new(info::list(commit)))))
+            }
+            MODE_COMMIT_INFO => Ok(Some((node.repo, Arc::new(info::list(node.oid))))),
+            MODE_PARENTS => {
+                let parents = info::list_parents(self.repo(node.repo)?, node.oid)?;
+                Ok(Some((node.repo, Arc::new(parents))))
+            }
+            MODE_PARENT_DIRS => Ok(Some((node.repo, Arc::new(CachedTree::from_entries(Vec::new()))))),
+            _ => Ok(None)
+        }
+    }
+
+    fn resolve_gitlink(&mut self, repo: usize, commit: Oid) -> error::Result<Option<(usize, Oid)>> {
+        if let Some(&res) = self.state().gitlinks.get(&(repo, commit)) {
+            return Ok(res);
+        }
+
+        let known = self.state().submodules.get(&repo).cloned();
+        let candidates = match known {
+            Some(candidates) => candidates,
+            None => {
+                let found = submodules::open_submodule_repos(self.repo(repo)?);
+
+                let mut state = self.state();
+                if !state.submodules.contains_key(&repo) {
+                    let mut indices = Vec::new();
+                    for sub in found {
+                        state.repo_paths.push(sub.path().to_path_buf());
+                        indices.push(state.repo_paths.len() - 1);
+                    }
+                    state.submodules.insert(repo, indices);
+                }
+                state.submodules[&repo].clone()
+            }
+        };
+
+        let mut res = None;
+        for sub in candidates {
+            if let Ok(c) = self.repo(sub)?.find_commit(commit) {
+                res = Some((sub, c.tree_id()));
+                break;
+            }
+        }
+
+        self.state().gitlinks.insert((repo, commit), res);
+        Ok(res)
+    }
+
+    fn get_tree(&mut self, repo: usize, oid: Oid) -> error::Result<Arc<CachedTree>> {
+        if let Some(tree) = self.state().objects.get_tree(&oid) {
+            return Ok(tree);
+        }
+
+        let tree = Arc::new(CachedTree::new(&self.repo(repo)?.find_tree(oid)?));
+
+        self.state().objects.insert_tree(oid, tree.clone());
+
+        Ok(tree)
+    }
+
+    fn get_blob(&mut self, node: &Node) -> error::Result<Arc<Vec<u8>>> {
+        if let Some(blob) = self.state().objects.get_blob(&node.oid) {
+            return Ok(blob);
+        }
+
+        let blob = Arc::new(self.repo(node.repo)?.find_blob(node.oid)?.content().to_vec());
+
+        self.state().objects.insert_blob(node.oid, blob.clone());
+
+        Ok(blob)
+    }
+
+    /// The content of a file or symlink. Most are blobs, but commit
+    /// metadata is made up on the spot.
+    fn get_content(&mut self, ino: u64, node: &Node) -> error::Result<Arc<Vec<u8>>> {
+        match node.mode {
+            MODE_INFO_FILE => {
+                let name = match self.state().nodes.get(ino) {
+                    Some(inode) => inode.name.clone(),
+                    None => return Err(Error::UnknownInode(ino))
+                };
+                Ok(Arc::new(info::content(self.repo(node.repo)?, node.oid, &name)?))
+            }
+            MODE_PARENT_LINK => {
+                let refs = get_node(&self.state().nodes, FUSE_ROOT_ID)?.mode == MODE_REFS;
+                Ok(Arc::new(info::link_target(&self.get_path(ino)?, node.oid, refs)))
+            }
+            _ => self.get_blob(node)
+        }
+    }
+
+    /// The size of a blob, read from the object header so the blob itself
+    /// never has to be inflated just to be stat'ed.
+    fn get_size(&mut self, ino: u64, node: &Node) -> error::Result<u64> {
+        if let FileType::Directory = get_file_type(node)? {
+            return Ok(0);
+        }
+
+        if let MODE_INFO_FILE | MODE_PARENT_LINK = node.mode {
+            return Ok(self.get_content(ino, node)?.len() as u64);
+        }
+
+        if let Some(&size) = self.state().sizes.get(&node.oid) {
+            return Ok(size);
+        }
+
+        let (size, _) = self.repo(node.repo)?.odb()?.read_header(node.oid)?;
+
+        let mut state = self.state();
+        if state.sizes.len() >= MAX_CACHED_SIZES {
+            state.sizes.clear();
+        }
+        state.sizes.insert(node.oid, size as u64);
+
+        Ok(size as u64)
+    }
+
+    /// A directory is linked from its parent, from its own `.` and from the
+    /// `..` of each subdirectory; anything else has just the one link.
+    fn get_nlink(&mut self, ino: u64, node: &Node) -> error::Result<u32> {
+        // Levels of ref names are not cached, and listing every ref on each
+        // stat is too costly. One link is what find takes to mean that the
+        // count says nothing about subdirectories.
+        if node.mode == MODE_REFS {
+            return Ok(1);
+        }
+
+        match self.list_dir(ino, node)? {
+            Some((_, tree)) => Ok(2 + tree.subdirs as u32),
+            None => Ok(1)
+        }
+    }
+
+    /// The commit that `ino` is part of in the refs view, and the repository
+    /// it is in: the one shown by the nearest ref or commit directory at or
+    /// above it, if there is one.
+    fn get_owning_commit(&mut self, ino: u64) -> error::Result<Option<(usize, Oid)>> {
+        let (dir, node) = {
+            let state = self.state();
+            let mut cur = ino;
+            loop {
+                let node = get_node(&state.nodes, cur)?;
+                match node.mode {
+                    MODE_REF | MODE_REF_INFO | MODE_COMMIT_DIR | MODE_COMMIT_INFO => break (cur, node),
+                    _ if cur == FUSE_ROOT_ID => return Ok(None),
+                    _ => cur = get_node_parent(&state.nodes, cur)?
+                }
+            }
+        };
+
+        match node.mode {
+            MODE_REF => {
+                let path = self.get_path(dir)?;
+                let commit = refs::resolve_commit(self.repo(node.repo)?, &path)?;
+                Ok(commit.map(|commit| (node.repo, commit)))
+            }
+            MODE_REF_INFO => {
+                let path = self.get_path(dir)?;
+                Ok(Some((node.repo, refs::resolve_info(self.repo(node.repo)?, &path)?)))
+            }
+            _ => Ok(Some((node.repo, node.oid)))
+        }
+    }
+
+    /// The (modified, created) times to report for `ino`. Anything below a
+    /// ref or commit directory has the time of that commit. Otherwise, until
+    /// the history index is ready, and for paths inside submodules, whose
+    /// history lives in another repository, both are the mounted commit's
+    /// time.
+    fn get_times(&mut self, ino: u64, node: &Node) -> error::Result<(Timespec, Timespec)> {
+        if let Some((repo, commit)) = self.get_owning_commit(ino)? {
+            let time = get_commit_time(&self.repo(repo)?.find_commit(commit)?);
+            return Ok((time, time));
+        }
+
+        let state = self.state();
+        let fallback = (state.commit_time, state.commit_time);
+
+        if state.times == Times::Commit || node.repo != 0 {
+            return Ok(fallback);
+        }
+
+        let history = match state.history {
+            Some(ref history) => history,
+            None => return Ok(fallback)
+        };
+
+        let path = match state.nodes.path(ino) {
+            Some(path) => path,
+            None => return Err(Error::UnknownInode(ino))
+        };
+
+        Ok(match history.get(&path) {
+            Some(h) => (get_change_time(&h.modified), get_change_time(&h.added)),
+            None => fallback
+        })
+    }
+
+    /// Count the blocks and entries of the whole mounted tree. This reads
+    /// every object header once per mounted revision; submodules are not
+    /// descended into.
+    fn get_usage(&mut self) -> error::Result<Usage> {
+        let root = get_node(&self.state().nodes, FUSE_ROOT_ID)?;
+
+        // A view of every ref has no single tree to count
+        if root.mode != MODE_TREE {
+            return Ok(Usage { blocks: 0, files: 1 });
+        }
+
+        if let Some((oid, usage)) = self.state().usage {
+            if oid == root.oid {
+                return Ok(usage);
+            }
+        }
+
+        let usage = {
+            let repo = self.repo(root.repo)?;
+            let odb = repo.odb()?;
+
+            // The root itself is one of the files
+            let mut usage = Usage { blocks: 0, files: 1 };
+            let mut res = Ok(());
+
+            let walked = repo.find_tree(root.oid)?.walk(TreeWalkMode::PreOrder, |_, entry| {
+                usage.files += 1;
+                if entry.kind() == Some(ObjectType::Blob) {
+                    match odb.read_header(entry.id()) {
+                        Ok((size, _)) => usage.blocks += (size as u64).div_ceil(BLOCK_SIZE),
+                        Err(e) => {
+                            res = Err(e);
+                            return TreeWalkResult::Abort;
+                        }
+                    }
+                }
+                TreeWalkResult::Ok
+            });
+
+            // An aborted walk fails with an error of its own, which says
+            // nothing about why it was aborted
+            res?;
+            walked?;
+
+            usage
+        };
+
+        self.state().usage = Some((root.oid, usage));
+
+        Ok(usage)
+    }
+
+    /// Show the tree of `commit` from now on. Every inode the kernel may
+    /// still hold, through a cached entry, a working directory or an open
+    /// handle, is moved to whatever its path holds in the new tree, and
+    /// marked stale if the path is gone. A path that holds anything but a
+    /// directory, before or after, and changed is detached instead: the
+    /// inode keeps the old content and the path gets a new one.
+    ///
+    /// The new nodes are resolved against a snapshot of the inodes, without
+    /// the state lock, and then swapped in under it all at once, so no
+    /// request sees part of each tree. Lookups made in the meantime still
+    /// see the old tree; further passes, much smaller than the first, move
+    /// the inodes they allocate.
+    pub fn set_commit(&mut self, commit: &Commit) {
+        let root = Node { repo: 0, oid: commit.tree_id(), mode: MODE_TREE };
+
+        // Inodes already moved, with the path they were moved for, since an
+        // evicted number may be given to another path
+        let mut moved: HashMap<u64, (u64, OsString)> = HashMap::new();
+        let mut first = true;
+
+        loop {
+            // The walk starts at the root on the first pass. After that it
+            // starts at each moved directory that gained children meanwhile;
+            // those may have been looked up in either tree, so none of them
+            // is skipped.
+            let (old_root, inodes, mut pending) = {
+                let state = self.state();
+                let old_root = get_node(&state.nodes, FUSE_ROOT_ID).unwrap();
+
+                let inodes: Vec<Snapshot> = state.nodes.iter().filter(|&(ino, inode)| {
+                    let done = match moved.get(&ino) {
+                        Some(&(parent, ref name)) => parent == inode.parent && *name == inode.name,
+                        None => false
+                    };
+                    ino != FUSE_ROOT_ID && !inode.stale && !inode.detached && !done
+                }).map(|(ino, inode)| Snapshot {
+                    ino: ino,
+                    parent: inode.parent,
+                    name: inode.name.clone(),
+                    node: inode.node
+                }).collect();
+
+                let mut pending = Vec::new();
+                if first {
+                    pending.push((FUSE_ROOT_ID, Some(old_root), Some(root)));
+                } else {
+                    let found: HashSet<u64> = inodes.iter().map(|inode| inode.ino).collect();
+                    let mut starts: Vec<u64> = inodes.iter().map(|inode| inode.parent).filter(|p| !found.contains(p)).collect();
+                    starts.sort();
+                    starts.dedup();
+
+                    for parent in starts {
+                        let node = state.nodes.get(parent).filter(|inode| !inode.stale).map(|inode| inode.node);
+                        pending.push((parent, None, node));
+                    }
+                }
+
+                (old_root, inodes, pending)
+            };
+
+            if !first && inodes.is_empty() {
+                break;
+            }
+
+            let mut children: HashMap<u64, Vec<&Snapshot>> = HashMap::new();
+            for inode in inodes.iter() {
+                children.entry(inode.parent).or_default().push(inode);
+            }
+
+            // Parents are resolved before their children. Objects are
+            // content-addressed, so below a node that did not change nothing
+            // can have changed either. The root is the exception: its
+            // `.commit` follows the commit, which can move without changing
+            // the tree.
+            let mut updates = Vec::new();
+            while let Some((ino, old, new)) = pending.pop() {
+                if old.is_some() && old == new && ino != FUSE_ROOT_ID {
+                    continue;
+                }
+
+                for &child in children.get(&ino).into_iter().flatten() {
+                    let child_new = match new {
+                        Some(ref node) => match self.resolve_child(ino, node, &child.name, commit.id()) {
+                            Ok(found) => found,
+                            Err(e) => {
+                                println!("failed to resolve {:?} in the new tree: {}", child.name, e);
+                                None
+                            }
+                        },
+                        None => None
+                    };
+
+                    // The kernel keeps the pages and size of a file with its
+                    // inode, where a file opened before could read pages of
+                    // the new content or be cut off at its size. Anything but
+                    // a directory that changed gets a new inode instead, and
+                    // this one goes on showing what it did.
+                    let detach = match child_new {
+                        Some(node) => node != child.node && !(is_dir(&node) && is_dir(&child.node)),
+                        None => false
+                    };
+
+                    updates.push((child, child_new, detach));
+                    if !detach {
+                        pending.push((child.ino, Some(child.node), child_new));
+                    }
+                }
+            }
+
+            let mut state = self.state();
+
+            // Only this thread moves the root, but should anything else have
+            // moved it, what was resolved is no longer what it shows
+            if get_node(&state.nodes, FUSE_ROOT_ID).unwrap() != old_root {
+                moved.clear();
+                first = true;
+                continue;
+            }
+
+            if first {
+                state.commit = commit.id();
+                state.commit_time = get_commit_time(commit);
+                state.nodes.set_node(FUSE_ROOT_ID, root);
+            }
+
+            for (child, new, detach) in updates {
+                match state.nodes.get(child.ino) {
+                    Some(inode) if inode.parent == child.parent && inode.name == child.name => {}
+                    _ => continue
+                }
+
+                match new {
+                    Some(_) if detach => state.nodes.detach(child.ino),
+                    Some(node) => state.nodes.set_node(child.ino, node),
+                    None => state.nodes.mark_stale(child.ino)
+                }
+            }
+
+            for inode in inodes {
+                moved.insert(inode.ino, (inode.parent, inode.name));
+            }
+            first = false;
+        }
+    }
+
+    /// What `name` is in the directory `node`, which is `parent`, once
+    /// `commit` is mounted.
+    fn resolve_child(&mut self, parent: u64, node: &Node, name: &OsStr, commit: Oid) -> error::Result<Option<Node>> {
+        let (repo, tree) = match self.list_dir(parent, node)? {
+            Some(dir) => dir,
+            None => return Ok(None)
+        };
+
+        let entry = match tree.get(name) {
+            Some(entry) => entry.clone(),
+            None => match self.lookup_unlisted(parent, node, repo, Path::new(name), commit) {
+                Ok(entry) => entry,
+                Err(Error::NotFound) => return Ok(None),
+                Err(e) => return Err(e)
+            }
+        };
+
+        Ok(Some(get_entry_node(repo, &entry)))
+    }
+
+    /// Names that can be looked up without being listed: the commits in
+    /// `commits/`, and at the root of a single revision the metadata of the
+    /// mounted commit and of the commits its `parents/` links lead to.
+    /// Leaving the latter out of the listing keeps the mount identical to a
+    /// checkout for anything that walks it.
+    fn lookup_unlisted(&mut self, parent: u64, node: &Node, repo: usize, name: &Path, commit: Oid) -> error::Result<CachedEntry> {
+        match node.mode {
+            MODE_COMMITS => refs::lookup_commit(self.repo(repo)?, name.as_os_str()),
+            MODE_TREE if parent == FUSE_ROOT_ID && name.as_os_str() == info::SUFFIX => Ok(CachedEntry {
+                name: name.as_os_str().to_os_string(),
+                oid: commit,
+                mode: MODE_COMMIT_INFO
+            }),
+            MODE_TREE if parent == FUSE_ROOT_ID && name.as_os_str() == info::PARENTS_DIR => Ok(CachedEntry {
+                name: name.as_os_str().to_os_string(),
+                oid: Oid::zero(),
+                mode: MODE_PARENT_DIRS
+            }),
+            MODE_PARENT_DIRS => info::lookup_parent(self.repo(repo)?, name.as_os_str()),
+            _ => Err(Error::NotFound)
+        }
+    }
+
+    fn do_lookup(&mut self, parent: u64, name: &Path, owner: (u32, u32)) -> error::Result<FileAttr> {
+        let (ino, node) = loop {
+            let parent_node = get_node(&self.state().nodes, parent)?;
+            let (repo, tree) = match self.list_dir(parent, &parent_node)? {
+                Some(dir) => dir,
+                None => return Err(Error::NotADirectory(parent))
+            };
+
+            let entry = match tree.get(name.as_os_str()) {
+                Some(entry) => entry.clone(),
+                None => {
+                    let commit = self.state().commit;
+                    self.lookup_unlisted(parent, &parent_node, repo, name, commit)?
+                }
+            };
+
+            // The kernel holds a reference from here until it sends a forget.
+            // It is counted under the same lock that allocates the inode, so
+            // another worker cannot evict it while its attributes are being
+            // gathered.
+            let mut state = self.state();
+
+            // If the revision moved while the directory was being listed, the
+            // entry may be from the old tree; list it again
+            if get_node(&state.nodes, parent)? != parent_node {
+                continue;
+            }
+
+            let (ino, _) = get_tree_entry_info(&mut state.nodes, parent, repo, &entry)?;
+            state.nodes.lookup(ino);
+
+            // The directory was just listed, so the entry is up to date; in
+            // the refs view this is how inodes follow their ref
+            let node = get_entry_node(repo, &entry);
+            state.nodes.set_node(ino, node);
+            break (ino, node);
+        };
+
+        let attr = self.get_node_attr(ino, &node, owner);
+
+        // No entry is sent on failure, so nothing will forget this reference
+        if attr.is_err() {
+            self.state().nodes.forget(ino, 1);
+        }
+
+        attr
+    }
+
+    fn get_node_attr(&mut self, ino: u64, node: &Node, owner: (u32, u32)) -> error::Result<FileAttr> {
+        let size = self.get_size(ino, node)?;
+        let nlink = self.get_nlink(ino, node)?;
+        let times = self.get_times(ino, node)?;
+
+        get_attr(ino, node, size, nlink, times, owner)
+    }
+
+    fn do_getattr(&mut self, ino: u64, owner: (u32, u32)) -> error::Result<FileAttr> {
+        let node = get_node(&self.state().nodes, ino)?;
+
+        self.get_node_attr(ino, &node, owner)
+    }
+
+    fn do_open(&mut self, ino: u64) -> error::Result<u64> {
+        let mut state = self.state();
+
+        let node = get_node(&state.nodes, ino)?;
+        if let FileType::Directory = get_file_type(&node)? {
+            return Err(Error::IsADirectory(ino));
+        }
+
+        Ok(state.files.insert(OpenFile {
+            node: node,
+            content: None
+        }))
+    }
+
+    fn do_read(&mut self, ino: u64, fh: u64, offset: u64, size: u32) -> error::Result<Vec<u8>> {
+        let node = {
+            let mut state = self.state();
+            match state.files.get_mut(fh) {
+                Some(file) => {
+                    if let Some(ref content) = file.content {
+                        return Ok(content[read_range(content.len(), offset, size)].to_vec());
+                    }
+                    file.node
+                }
+                // Not opened through us; serve the read without a handle
+                None => get_node(&state.nodes, ino)?
+            }
+        };
+
+        if let FileType::Directory = get_file_type(&node)? {
+            return Err(Error::IsADirectory(ino));
+        }
+
+        let content = self.get_content(ino, &node)?;
+
+        if let Some(file) = self.state().files.get_mut(fh) {
+            file.content = Some(content.clone());
+        }
+
+        Ok(content[read_range(content.len(), offset, size)].to_vec())
+    }
+
+    fn do_readlink(&mut self, ino: u64) -> error::Result<Vec<u8>> {
+        let node = get_node(&self.state().nodes, ino)?;
+        if node.mode & 0o170000 != MODE_LINK {
+            return Err(Error::NotASymlink(ino));
+        }
+
+        Ok(self.get_content(ino, &node)?.to_vec())
+    }
+
+    fn do_opendir(&mut self, ino: u64) -> error::Result<u64> {
+        let (repo, tree) = self.get_dir(ino)?;
+
+        Ok(self.state().dirs.insert(OpenDir {
+            repo: repo,
+            tree: tree
+        }))
+    }
+
+    /// Hand the entries from `offset` on to `add`, which gives true once the
+    /// reply is full. Entries of a listing pinned by `opendir` may be out of
+    /// date, so inodes that already exist are never changed to match them.
+    fn do_readdir<F>(&mut self, ino: u64, fh: u64, offset: u64, mut add: F) -> error::Result<()>
+        where F: FnMut(u64, u64, FileType, &OsStr) -> bool {
+        let opened = self.state().dirs.get_mut(fh).map(|dir| (dir.repo, dir.tree.clone()));
+        let (repo, tree) = match opened {
+            Some(dir) => dir,
+            // Not opened through us; list the directory as it is now
+            None => self.get_dir(ino)?
+        };
+
+        let mut state = self.state();
+
+        let parent = get_node_parent(&state.nodes, ino)?;
+
+        // Entry k is ".", ".." and then the tree entries in order. Each entry
+        // carries the offset to resume from after it, so the kernel can pick
+        // up where a full reply buffer cut us off.
+        for k in offset as usize .. tree.len() + 2 {
+            let next = k as u64 + 1;
+
+            let full = match k {
+                0 => {
+                    // println!("  add {} {} Directory .", ino, next);
+                    add(ino, next, FileType::Directory, OsStr::new("."))
+                }
+                1 => {
+                    // println!("  add {} {} Directory ..", parent, next);
+                    add(parent, next, FileType::Directory, OsStr::new(".."))
+                }
+                _ => {
+                    let entry = &tree.entries[k - 2];
+                    let (child, kind) = get_tree_entry_info(&mut state.nodes, ino, repo, entry)?;
+
+                    // println!("  add {} {} {:?} {:?}", child, next, kind, entry.name);
+                    add(child, next, kind, &entry.name)
+                }
+            };
+
+            if full {
+                break;
+            }
+        }
+
+        state.nodes.evict();
+
+        Ok(())
+    }
+
+    pub fn lookup(&mut self, parent: u64, name: &Path, owner: (u32, u32), reply: ReplyEntry) {
+        // println!("lookup {:?} {:?}", parent, name);
+
+        match self.do_lookup(parent, name, owner) {
+            Ok(attr) => {
+                // println!("  entry {:?}", attr);
+                reply.entry(&TTL, &attr, 0);
+            }
+            Err(e) => {
+                // Missing names are routine (shells probe for them constantly)
+                if let Error::NotFound = e {} else {
+                    println!("lookup({}, {:?}) error: {}", parent, name, e);
+                }
+                reply.error(e.errno());
+            }
+        }
+    }
+
+    pub fn getattr(&mut self, ino: u64, owner: (u32, u32), reply: ReplyAttr) {
+        // println!("getattr {:?}", ino);
+
+        match self.do_getattr(ino, owner) {
+            Ok(attr) => {
+                // println!("  attr {:?}", attr);
+                reply.attr(&TTL, &attr);
+            }
+            Err(e) => {
+                println!("getattr({}) error: {}", ino, e);
+                reply.error(e.errno());
+            }
+        }
+    }
+
+    pub fn readlink(&mut self, ino: u64, reply: ReplyData) {
+        // println!("readlink {:?}", ino);
+
+        match self.do_readlink(ino) {
+            Ok(target) => reply.data(&target),
+            Err(e) => {
+                println!("readlink({}) error: {}", ino, e);
+                reply.error(e.errno());
+            }
+        }
+    }
+
+    pub fn open(&mut self, ino: u64, _flags: u32, reply: ReplyOpen) {
+        // println!("open {:?} {:?}", ino, _flags);
+
+        match self.do_open(ino) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(e) => {
+                println!("open({}) error: {}", ino, e);
+                reply.error(e.errno());
+            }
+        }
+    }
+
+    pub fn read(&mut self, ino: u64, fh: u64, offset: u64, size: u32, reply: ReplyData) {
+        // println!("read {:?} {:?} {:?} {:?}", ino, fh, offset, size);
+
+        match self.do_read(ino, fh, offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(e) => {
+                println!("read({}, {}, {}) error: {}", ino, offset, size, e);
+                reply.error(e.errno());
+            }
+        }
+    }
+
+    pub fn statfs(&mut self, reply: ReplyStatfs) {
+        // println!("statfs");
+
+        match self.get_usage() {
+            // Nothing can be written, so nothing is free
+            Ok(usage) => reply.statfs(usage.blocks, 0, 0, usage.files, 0, BLOCK_SIZE as u32, MAX_NAME_LEN, BLOCK_SIZE as u32),
+            Err(e) => {
+                println!("statfs() error: {}", e);
+                reply.error(e.errno());
+            }
+        }
+    }
+
+    pub fn opendir(&mut self, ino: u64, _flags: u32, reply: ReplyOpen) {
+        // println!("opendir {:?} {:?}", ino, _flags);
+
+        match self.do_opendir(ino) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(e) => {
+                println!("opendir({}) error: {}", ino, e);
+                reply.error(e.errno());
+            }
+        }
+    }
+
+    pub fn readdir(&mut self, ino: u64, fh: u64, offset: u64, mut reply: ReplyDirectory) {
+        // println!("readdir {:?} {:?} {:?}", ino, fh, offset);
+
+        match self.do_readdir(ino, fh, offset, |child, next, kind, name| reply.add(child, next, kind, name)) {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                println!("readdir({}, {}) error: {}", ino, offset, e);
+                reply.error(e.errno());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::path::{Component, Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+
+    use fuse::FUSE_ROOT_ID;
+
+    use testutil::TestRepo;
+
+    use libc::ENOENT;
+
+    use super::{get_node, read_range, State, Times, Worker};
+
+    const OWNER: (u32, u32) = (0, 0);
+
+    fn mount(repo: &TestRepo, rev: &str, refs: bool) -> Worker {
+        let commit = repo.repo.revparse_single(rev).unwrap().peel_to_commit().unwrap();
+        let state = State::new(&repo.repo, &commit, 1 << 20, Times::Commit, refs);
+        Worker::new(Arc::new(Mutex::new(state)))
+    }
+
+    fn lookup_path(worker: &mut Worker, path: &str) -> u64 {
+        Path::new(path).iter().fold(FUSE_ROOT_ID, |parent, name| {
+            worker.do_lookup(parent, Path::new(name), OWNER).unwrap().ino
+        })
+    }
+
+    fn read_path(worker: &mut Worker, path: &str) -> String {
+        let ino = lookup_path(worker, path);
+        let fh = worker.do_open(ino).unwrap();
+        String::from_utf8(worker.do_read(ino, fh, 0, 4096).unwrap()).unwrap()
+    }
+
+    // Where the symlink at `link` leads, as a path from the root
+    fn follow_link(link: &str, target: &str) -> String {
+        let path = Path::new(link).parent().unwrap().join(target);
+        let path = path.components().fold(PathBuf::new(), |mut p, c| {
+            match c {
+                Component::ParentDir => { p.pop(); }
+                c => p.push(c)
+            }
+            p
+        });
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn single_revision_has_unlisted_commit_metadata() {
+        let repo = TestRepo::new("worker-root-info");
+        let old = repo.commit(None, 100, &[("a", "1")]);
+        let new = repo.commit(Some(old), 200, &[("a", "2")]);
+
+        let mut worker = mount(&repo, &new.to_string(), false);
+
+        assert_eq!(read_path(&mut worker, ".commit/oid"), format!("{}\n", new));
+        assert_eq!(read_path(&mut worker, ".commit/date"), "Thu Jan 01 00:03:20 1970 +0000\n");
+
+        let link = lookup_path(&mut worker, ".commit/parents/0");
+        let target = String::from_utf8(worker.do_readlink(link).unwrap()).unwrap();
+        assert_eq!(target, format!("../../.commit-parents/{}", old));
+
+        // The link resolves to the parent's metadata
+        let path = follow_link(".commit/parents/0", &target);
+        assert_eq!(read_path(&mut worker, &format!("{}/oid", path)), format!("{}\n", old));
+
+        let (_, root) = worker.get_dir(FUSE_ROOT_ID).unwrap();
+        assert!(root.get(OsStr::new(".commit")).is_none());
+        assert!(root.get(OsStr::new(".commit-parents")).is_none());
+    }
+
+    #[test]
+    fn refs_have_commit_metadata_next_to_them() {
+        let repo = TestRepo::new("worker-ref-info");
+        let old = repo.commit(None, 100, &[("a", "1")]);
+        let new = repo.commit(Some(old), 200, &[("a", "2")]);
+        repo.repo.reference("refs/heads/main", new, true, "").unwrap();
+        repo.repo.set_head("refs/heads/main").unwrap();
+
+        let mut worker = mount(&repo, "main", true);
+
+        assert_eq!(read_path(&mut worker, "HEAD.commit/oid"), format!("{}\n", new));
+        assert_eq!(read_path(&mut worker, "branches/main.commit/tree"),
+                   format!("{}\n", repo.repo.find_commit(new).unwrap().tree_id()));
+
+        let link = lookup_path(&mut worker, "branches/main.commit/parents/0");
+        let target = String::from_utf8(worker.do_readlink(link).unwrap()).unwrap();
+        assert_eq!(target, format!("../../../commits/{}", old));
+
+        // The link resolves to the parent's tree
+        let path = follow_link("branches/main.commit/parents/0", &target);
+        assert_eq!(read_path(&mut worker, &format!("{}/a", path)), "1");
+    }
+
+    #[test]
+    fn moving_the_commit_moves_every_known_inode() {
+        let repo = TestRepo::new("worker-follow");
+        let old = repo.commit(None, 100, &[("a", "1"), ("d/b", "1"), ("gone/x", "1")]);
+        let new = repo.commit(Some(old), 200, &[("a", "22"), ("d/b", "1"), ("d/c", "1")]);
+
+        let mut worker = mount(&repo, &old.to_string(), false);
+
+        let a = lookup_path(&mut worker, "a");
+        let b = lookup_path(&mut worker, "d/b");
+        let gone = lookup_path(&mut worker, "gone");
+        let x = lookup_path(&mut worker, "gone/x");
+        let info = lookup_path(&mut worker, ".commit");
+        let fh = worker.do_open(a).unwrap();
+        let b_node = get_node(&worker.state().nodes, b).unwrap();
+
+        let commit = repo.repo.find_commit(new).unwrap();
+        worker.set_commit(&commit);
+
+        assert_eq!(get_node(&worker.state().nodes, b).unwrap(), b_node);
+        assert_eq!(worker.do_getattr(x, OWNER).unwrap_err().errno(), ENOENT);
+        assert_eq!(worker.do_getattr(gone, OWNER).unwrap_err().errno(), ENOENT);
+
+        assert_eq!(get_node(&worker.state().nodes, info).unwrap().oid, new);
+        assert_eq!(read_path(&mut worker, ".commit/oid"), format!("{}\n", new));
+
+        // A file that changed is found under a new inode, so the kernel never
+        // mixes up what it cached for the old content with the new one
+        assert_eq!(worker.do_getattr(a, OWNER).unwrap().size, 1);
+        assert_eq!(worker.do_read(a, fh, 0, 4096).unwrap(), b"1");
+        assert_ne!(lookup_path(&mut worker, "a"), a);
+        assert_eq!(read_path(&mut worker, "a"), "22");
+        assert_eq!(read_path(&mut worker, "d/c"), "1");
+        assert!(worker.do_lookup(FUSE_ROOT_ID, Path::new("gone"), OWNER).is_err());
+
+        // A commit that leaves the tree alone still moves the metadata
+        let same = repo.commit(Some(new), 300, &[("a", "22"), ("d/b", "1"), ("d/c", "1")]);
+        let commit = repo.repo.find_commit(same).unwrap();
+        worker.set_commit(&commit);
+
+        assert_eq!(get_node(&worker.state().nodes, info).unwrap().oid, same);
+        assert_eq!(read_path(&mut worker, ".commit/oid"), format!("{}\n", same));
+    }
+
+    #[test]
+    fn pinned_listing_leaves_known_inodes_alone() {
+        let repo = TestRepo::new("worker-pinned-readdir");
+        let old = repo.commit(None, 100, &[("d/a/x", "1")]);
+        let new = repo.commit(Some(old), 200, &[("d/a/x", "22"), ("d/b", "1")]);
+
+        let mut worker = mount(&repo, &old.to_string(), false);
+
+        let d = lookup_path(&mut worker, "d");
+        let a = lookup_path(&mut worker, "d/a");
+        let fh = worker.do_opendir(d).unwrap();
+
+        let commit = repo.repo.find_commit(new).unwrap();
+        worker.set_commit(&commit);
+        let a_node = get_node(&worker.state().nodes, a).unwrap();
+
+        let mut listed = Vec::new();
+        worker.do_readdir(d, fh, 0, |ino, _, _, name| {
+            listed.push((ino, name.to_os_string()));
+            false
+        }).unwrap();
+
+        // The listing is the one from before the move, under the same inodes
+        let names: Vec<_> = listed.iter().map(|e| e.1.to_str().unwrap()).collect();
+        assert_eq!(names, [".", "..", "a"]);
+        assert_eq!(listed[2].0, a);
+
+        assert_eq!(get_node(&worker.state().nodes, a).unwrap(), a_node);
+        assert_eq!(read_path(&mut worker, "d/a/x"), "22");
+    }
+
+    #[test]
+    fn ref_directories_carry_their_commit_time() {
+        let repo = TestRepo::new("worker-ref-times");
+        let old = repo.commit(None, 100, &[("d/a", "1")]);
+        let new = repo.commit(Some(old), 200, &[("d/a", "2")]);
+        repo.repo.reference("refs/heads/old", old, true, "").unwrap();
+        repo.repo.reference("refs/heads/main", new, true, "").unwrap();
+
+        let mut worker = mount(&repo, "main", true);
+
+        for &(path, time) in &[("branches/old", 100), ("branches/old/d/a", 100), ("branches/main/d", 200)] {
+            let ino = lookup_path(&mut worker, path);
+            let attr = worker.do_getattr(ino, OWNER).unwrap();
+            assert_eq!((path, attr.mtime.sec, attr.crtime.sec), (path, time, time));
+        }
+    }
+
+    #[test]
+    fn read_within_file() {
+        assert_eq!(read_range(10, 0, 4), 0..4);
+        assert_eq!(read_range(10, 3, 4), 3..7);
+    }
+
+    #[test]
+    fn read_whole_file() {
+        assert_eq!(read_range(10, 0, 10), 0..10);
+    }
+
+    #[test]
+    fn read_ending_exactly_at_eof() {
+        assert_eq!(read_range(10, 6, 4), 6..10);
+    }
+
+    #[test]
+    fn read_past_eof_is_short() {
+        assert_eq!(read_range(10, 0, 4096), 0..10);
+        assert_eq!(read_range(10, 9, 4096), 9..10);
+    }
+
+    #[test]
+    fn read_at_eof_is_empty() {
+        assert!(read_range(10, 10, 4096).is_empty());
+    }
+
+    #[test]
+    fn read_beyond_eof_is_empty() {
+        assert!(read_range(10, 11, 1).is_empty());
+        assert!(read_range(10, u64::MAX, u32::MAX).is_empty());
+    }
+
+    #[test]
+    fn read_empty_file() {
+        assert!(read_range(0, 0, 4096).is_empty());
+        assert!(read_range(0, 1, 4096).is_empty());
+    }
+
+    #[test]
+    fn read_zero_bytes() {
+        assert!(read_range(10, 0, 0).is_empty());
+        assert!(read_range(10, 5, 0).is_empty());
+    }
+
+    #[test]
+    fn read_size_larger_than_remaining_does_not_overflow() {
+        assert_eq!(read_range(usize::MAX, (usize::MAX - 1) as u64, u32::MAX),
+                   usize::MAX - 1 .. usize::MAX);
+    }
+}